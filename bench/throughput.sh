@@ -0,0 +1,191 @@
+#!/usr/bin/env bash
+# Measures the throughput of a connection forwarded by tunglo through a local OpenSSH
+# server, for two revisions:
+#
+#   bench/throughput.sh <before-rev> <after-rev>
+#
+# To compare the copy loops before and after the reusable buffers, pass the commit
+# that replaced the 4 KiB buffer allocated per read with a 64 KiB one reused per
+# direction ("Copy forwarded data through reusable buffers"), and its parent:
+#
+#   rev=$(git log -1 --format=%H --grep='reusable buffers')
+#   bench/throughput.sh "$rev~1" "$rev"
+#
+# Any two revisions can be compared, both must build.
+#
+# Needs sshd, ssh-keygen, python3, git and cargo. Everything runs on 127.0.0.1 as the
+# current user, in a temporary directory removed at the end. SIZE_MB megabytes are
+# sent in each direction (download: service -> client, upload: client -> service),
+# RUNS times per revision, and the median is reported.
+#
+# Results: not measured yet. The buffers were changed on a machine without sshd, where
+# tunglo's dependencies couldn't be fetched either. Paste the output here with the
+# machine it ran on, e.g. `SIZE_MB=512 RUNS=3` on an otherwise idle host.
+set -euo pipefail
+
+if [ $# -ne 2 ]; then
+    echo "usage: $0 <before-rev> <after-rev>" >&2
+    exit 2
+fi
+BEFORE=$1
+AFTER=$2
+SIZE_MB=${SIZE_MB:-512}
+RUNS=${RUNS:-3}
+SSH_PORT=${SSH_PORT:-2222}
+REMOTE_PORT=${REMOTE_PORT:-9000}
+BACKEND_PORT=${BACKEND_PORT:-8000}
+
+REPO=$(git rev-parse --show-toplevel)
+SSHD=$(command -v sshd || echo /usr/sbin/sshd)
+WORK=$(mktemp -d)
+PIDS=()
+cleanup() {
+    for pid in "${PIDS[@]}"; do
+        kill "$pid" 2>/dev/null || true
+    done
+    for name in before after; do
+        git -C "$REPO" worktree remove --force "$WORK/src-$name" 2>/dev/null || true
+    done
+    rm -rf "$WORK"
+}
+trap cleanup EXIT
+
+cat >"$WORK/bench.py" <<'EOF'
+import socket, statistics, sys, time
+
+CHUNK = 64 * 1024
+
+def serve(port):
+    # "d": sends the requested bytes then closes, "u": reads until EOF then answers
+    import threading
+    def handle(conn):
+        with conn:
+            header = conn.recv(32).decode()
+            if not header:
+                return
+            mode, size = header.split()[:2]
+            if mode == "d":
+                data, left = b"x" * CHUNK, int(size)
+                while left > 0:
+                    conn.sendall(data[: min(CHUNK, left)])
+                    left -= CHUNK
+            else:
+                while conn.recv(CHUNK):
+                    pass
+                conn.sendall(b"ok")
+    server = socket.create_server(("127.0.0.1", port))
+    while True:
+        conn, _ = server.accept()
+        threading.Thread(target=handle, args=(conn,), daemon=True).start()
+
+def measure(port, mode, size):
+    conn = socket.create_connection(("127.0.0.1", port), timeout=120)
+    conn.sendall(f"{mode} {size}".ljust(32).encode())
+    started = time.monotonic()
+    if mode == "d":
+        received = 0
+        while chunk := conn.recv(CHUNK):
+            received += len(chunk)
+        assert received == size, f"received {received} bytes out of {size}"
+    else:
+        data, left = b"x" * CHUNK, size
+        while left > 0:
+            conn.sendall(data[: min(CHUNK, left)])
+            left -= CHUNK
+        # half-close: the answer comes once the service got everything
+        conn.shutdown(socket.SHUT_WR)
+        assert conn.recv(2) == b"ok"
+    return size / (time.monotonic() - started) / 2**20
+
+def wait(port):
+    for _ in range(300):
+        try:
+            socket.create_connection(("127.0.0.1", port), timeout=1).close()
+            return
+        except OSError:
+            time.sleep(0.1)
+    sys.exit(f"nothing listens on {port}")
+
+if __name__ == "__main__":
+    command, port = sys.argv[1], int(sys.argv[2])
+    if command == "serve":
+        serve(port)
+    elif command == "wait":
+        wait(port)
+    else:
+        runs = [measure(port, command, int(sys.argv[3])) for _ in range(int(sys.argv[4]))]
+        print(f"{statistics.median(runs):.1f}")
+EOF
+
+# a throwaway sshd accepting a throwaway key
+ssh-keygen -q -t ed25519 -N "" -f "$WORK/host_key"
+ssh-keygen -q -t ed25519 -N "" -f "$WORK/client_key"
+cp "$WORK/client_key.pub" "$WORK/authorized_keys"
+cat >"$WORK/sshd_config" <<EOF
+Port $SSH_PORT
+ListenAddress 127.0.0.1
+HostKey $WORK/host_key
+PidFile $WORK/sshd.pid
+AuthorizedKeysFile $WORK/authorized_keys
+PasswordAuthentication no
+KbdInteractiveAuthentication no
+UsePAM no
+StrictModes no
+AllowTcpForwarding yes
+EOF
+"$SSHD" -D -e -f "$WORK/sshd_config" 2>"$WORK/sshd.log" &
+PIDS+=($!)
+python3 "$WORK/bench.py" serve "$BACKEND_PORT" &
+PIDS+=($!)
+python3 "$WORK/bench.py" wait "$SSH_PORT"
+python3 "$WORK/bench.py" wait "$BACKEND_PORT"
+
+build() {
+    local name=$1 rev=$2
+    git -C "$REPO" worktree add --quiet --detach "$WORK/src-$name" "$rev"
+    # a shared target directory, only what changed is rebuilt
+    CARGO_TARGET_DIR="$WORK/target" cargo build --quiet --release \
+        --manifest-path "$WORK/src-$name/Cargo.toml"
+    cp "$WORK/target/release/tunglo" "$WORK/tunglo-$name"
+}
+
+run() {
+    local name=$1 rev=$2
+    mkdir -p "$WORK/run-$name/data"
+    cat >"$WORK/run-$name/tunglo.toml" <<EOF
+[storage]
+type = "local"
+[[tunnels]]
+name = "bench"
+remote_ssh_address = "127.0.0.1"
+remote_ssh_port = $SSH_PORT
+remote_ssh_user = "$(id -un)"
+private_key_path = "$WORK/client_key"
+remote_interface_address = "127.0.0.1"
+remote_interface_port = $REMOTE_PORT
+to_address = "127.0.0.1"
+to_port = $BACKEND_PORT
+type = "generic"
+EOF
+    (cd "$WORK/run-$name" && exec "$WORK/tunglo-$name" --config tunglo.toml \
+        >"$WORK/run-$name/tunglo.log" 2>&1) &
+    local pid=$!
+    PIDS+=($pid)
+    python3 "$WORK/bench.py" wait "$REMOTE_PORT"
+    local size=$((SIZE_MB * 1024 * 1024))
+    local download upload
+    download=$(python3 "$WORK/bench.py" d "$REMOTE_PORT" "$size" "$RUNS")
+    upload=$(python3 "$WORK/bench.py" u "$REMOTE_PORT" "$size" "$RUNS")
+    printf "%-8s %-12s %12s MiB/s %12s MiB/s\n" "$name" "$(git -C "$REPO" rev-parse --short "$rev")" \
+        "$download" "$upload"
+    kill "$pid"
+    wait "$pid" 2>/dev/null || true
+    # the remote port is released once the session is gone
+    sleep 1
+}
+
+build before "$BEFORE"
+build after "$AFTER"
+printf "%-8s %-12s %18s %18s\n" "" "revision" "download" "upload"
+run before "$BEFORE"
+run after "$AFTER"
@@ -6,4 +6,7 @@ pub(crate) struct TungloCli {
     /// custom config file
     #[arg(short, long)]
     pub config: Option<String>,
+    /// reload the configuration when the config file changes (it is always reloaded on SIGHUP)
+    #[arg(short, long)]
+    pub watch: bool,
 }
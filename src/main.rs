@@ -1,15 +1,21 @@
 use clap::Parser;
 use cli::TungloCli;
 use config::TungloConfig;
-use futures::future::join_all;
+use std::time::{Duration, SystemTime};
+use tokio::signal;
+use tracing::{error, info};
 use tracing_subscriber::fmt::format::FmtSpan;
-use tunneling::tunnel::{Tunnel, TunnelError};
+use tunneling::{manager::TunnelManager, state::StartupReport, tunnel::TunnelError};
 
 mod cli;
 mod config;
 mod storage;
 mod tunneling;
 
+const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 30;
+/// how often the config file is checked for changes when watching it
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
 #[tokio::main]
 pub async fn main() -> Result<(), TunnelError> {
     let subscriber = tracing_subscriber::fmt()
@@ -20,22 +26,205 @@ pub async fn main() -> Result<(), TunnelError> {
     tracing::subscriber::set_global_default(subscriber).unwrap();
 
     let cli = TungloCli::parse();
-    let config = std::fs::read_to_string(cli.config.unwrap_or(config::DEFAULT_PATH.to_string()))
-        .expect("error while reading config: ");
-    let loaded_config: TungloConfig = toml::from_str(&config).unwrap();
-    let mut tunnels: Vec<Tunnel> = loaded_config
-        .tunnels
-        .into_iter()
-        .map(|c| Tunnel::new(c, loaded_config.storage.clone()).unwrap())
-        .collect();
-
-    let mut handlers = vec![];
+    let config_path = cli.config.unwrap_or(config::DEFAULT_PATH.to_string());
+    let loaded_config = load_config(&config_path)?;
+    let drain_timeout = drain_timeout(&loaded_config);
+    let mut manager = TunnelManager::new(drain_timeout);
+    let started = SystemTime::now();
+    manager.apply(loaded_config, drain_timeout).await?;
+    // every tunnel connects on its own, the report comes once all of them tried once
+    let statuses = manager.subscribe();
+    tokio::spawn(async move { StartupReport::collect(started, statuses).await.log() });
 
-    for i in 0..tunnels.len() {
-        if let Some(tunnel) = tunnels.get_mut(i) {
-            handlers.push(tunnel.connect().await.unwrap());
+    let mut signals = Signals::new();
+    let mut config_modified = modified(&config_path);
+    let mut watch = tokio::time::interval(CONFIG_WATCH_INTERVAL);
+    loop {
+        let keep_running = tokio::select! {
+            signal = signals.recv() => match signal {
+                Signal::Shutdown => false,
+                Signal::Reload => {
+                    info!("received SIGHUP, reloading configuration");
+                    config_modified = modified(&config_path);
+                    reload(&mut manager, &config_path, &mut signals).await
+                }
+                Signal::Status => {
+                    log_status(&manager);
+                    true
+                }
+            },
+            _ = manager.all_failed() => {
+                error!("every tunnel failed, exiting");
+                manager.shutdown().await;
+                return Err(TunnelError::AllTunnelsFailed);
+            }
+            _ = watch.tick(), if cli.watch => {
+                let modified = modified(&config_path);
+                if modified != config_modified {
+                    info!("config file {config_path} changed, reloading configuration");
+                    config_modified = modified;
+                    reload(&mut manager, &config_path, &mut signals).await
+                } else {
+                    true
+                }
+            }
+        };
+        if !keep_running {
+            info!(
+                "shutting down, draining connections for up to {:?}",
+                manager.drain_timeout()
+            );
+            manager.shutdown().await;
+            break;
         }
     }
-    join_all(handlers).await;
     Ok(())
 }
+
+fn load_config(path: &str) -> Result<TungloConfig, TunnelError> {
+    let config = std::fs::read_to_string(path)
+        .map_err(|e| TunnelError::InvalidConfig(format!("cannot read {path}: {e}")))?;
+    toml::from_str(&config)
+        .map_err(|e| TunnelError::InvalidConfig(format!("cannot parse {path}: {e}")))
+}
+
+fn drain_timeout(config: &TungloConfig) -> Duration {
+    Duration::from_secs(config.drain_timeout.unwrap_or(DEFAULT_DRAIN_TIMEOUT_SECS))
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// applies the configuration found at `path`, unless asked to shut down meanwhile:
+/// returns whether tunglo keeps running. Restarted tunnels may take up to the drain
+/// timeout to stop, signals are handled in the meantime
+async fn reload(manager: &mut TunnelManager, path: &str, signals: &mut Signals) -> bool {
+    let shutdown = async {
+        // another SIGHUP changes nothing, the configuration is being read already, and
+        // the status is dumped once the tunnels settled
+        while !matches!(signals.recv().await, Signal::Shutdown) {}
+    };
+    tokio::select! {
+        _ = apply_config(manager, path) => true,
+        _ = shutdown => false,
+    }
+}
+
+/// applies the configuration found at `path`, keeping the running tunnels untouched
+/// when it is not valid
+async fn apply_config(manager: &mut TunnelManager, path: &str) {
+    let applied = match load_config(path) {
+        Ok(config) => {
+            let drain_timeout = drain_timeout(&config);
+            manager.apply(config, drain_timeout).await
+        }
+        Err(e) => Err(e),
+    };
+    match applied {
+        Ok(plan) if plan.is_empty() => info!("configuration reloaded, no tunnel changed"),
+        Ok(plan) => info!(
+            "configuration reloaded, started: {:?}, stopped: {:?}, restarted: {:?}, failed: {:?}",
+            plan.start, plan.stop, plan.restart, plan.failed
+        ),
+        Err(e) => error!(
+            "configuration rejected, the running tunnels are kept: {}",
+            e.to_string()
+        ),
+    }
+}
+
+/// logs where every tunnel is and the connections it forwards
+fn log_status(manager: &TunnelManager) {
+    let statuses = manager.statuses();
+    info!("status: {} tunnels", statuses.len());
+    for status in &statuses {
+        let since = status.since.elapsed().unwrap_or_default();
+        if status.state.is_terminal() {
+            info!(
+                "tunnel `{}`: {} for {:?}, last error: {}",
+                status.name,
+                status.state,
+                since,
+                status.last_error.as_deref().unwrap_or("none")
+            );
+        } else {
+            info!(
+                "tunnel `{}`: {} for {:?} through {}, remote ports {:?}, {} connections failed and {} refused so far",
+                status.name,
+                status.state,
+                since,
+                status.bastion.as_deref().unwrap_or("no bastion"),
+                status.remote_ports,
+                status.connections.failures,
+                status.connections.rejections
+            );
+        }
+    }
+    for (name, registry) in manager.connections() {
+        if registry.is_empty() {
+            continue;
+        }
+        info!("tunnel `{name}`: {} connections", registry.len());
+        for connection in registry.list() {
+            info!(
+                "tunnel `{name}`: connection {} from {} {} for {:?}, {} bytes in, {} bytes out",
+                connection.id,
+                connection.originator,
+                connection.state,
+                connection.started.elapsed().unwrap_or_default(),
+                connection.bytes_in,
+                connection.bytes_out
+            );
+        }
+    }
+}
+
+/// the signals tunglo reacts to
+enum Signal {
+    /// SIGTERM (sent by kubernetes when terminating the pod) or SIGINT
+    Shutdown,
+    /// SIGHUP
+    Reload,
+    /// SIGUSR1, logs the status of every tunnel
+    Status,
+}
+struct Signals {
+    #[cfg(unix)]
+    terminate: signal::unix::Signal,
+    #[cfg(unix)]
+    hangup: signal::unix::Signal,
+    #[cfg(unix)]
+    user_defined: signal::unix::Signal,
+}
+impl Signals {
+    fn new() -> Signals {
+        Signals {
+            #[cfg(unix)]
+            terminate: signal::unix::signal(signal::unix::SignalKind::terminate())
+                .expect("cannot listen for SIGTERM"),
+            #[cfg(unix)]
+            hangup: signal::unix::signal(signal::unix::SignalKind::hangup())
+                .expect("cannot listen for SIGHUP"),
+            #[cfg(unix)]
+            user_defined: signal::unix::signal(signal::unix::SignalKind::user_defined1())
+                .expect("cannot listen for SIGUSR1"),
+        }
+    }
+    async fn recv(&mut self) -> Signal {
+        #[cfg(unix)]
+        {
+            tokio::select! {
+                _ = self.terminate.recv() => Signal::Shutdown,
+                _ = signal::ctrl_c() => Signal::Shutdown,
+                _ = self.hangup.recv() => Signal::Reload,
+                _ = self.user_defined.recv() => Signal::Status,
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = signal::ctrl_c().await;
+            Signal::Shutdown
+        }
+    }
+}
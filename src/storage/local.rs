@@ -3,7 +3,10 @@ use std::sync::{Arc, Mutex};
 use async_trait::async_trait;
 use rusqlite::Connection;
 
-use super::{Storage, StorageError};
+use super::{
+    ACQUIRE_LEASE, CREATE_ALLOCATED_PORTS, CREATE_KNOWN_HOSTS, CREATE_LEASES, RELEASE_LEASE,
+    Storage, StorageError,
+};
 pub struct LocalStorage {
     connection: Arc<Mutex<rusqlite::Connection>>,
 }
@@ -13,6 +16,13 @@ impl LocalStorage {
             connection: Arc::new(Mutex::new(Connection::open("./data/known_hosts.db")?)),
         })
     }
+    /// a throwaway database, it stands in for rqlite (sqlite as well) in tests
+    #[cfg(test)]
+    pub fn in_memory() -> Result<Self, StorageError> {
+        Ok(LocalStorage {
+            connection: Arc::new(Mutex::new(Connection::open_in_memory()?)),
+        })
+    }
 }
 #[async_trait]
 impl Storage for LocalStorage {
@@ -41,12 +51,60 @@ impl Storage for LocalStorage {
         )?;
         Ok(())
     }
+    async fn get_allocated_port(
+        &self,
+        tunnel: &str,
+        server_address: &str,
+    ) -> Result<Option<u16>, StorageError> {
+        let conn = self.connection.clone();
+        let conn = conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare("select port from allocated_ports where tunnel = ?1 and hostname = ?2")?;
+        let mut query_mapped = stmt.query_map([tunnel, server_address], |row| row.get(0))?;
+        if let Some(v) = query_mapped.next() {
+            Ok(Some(v?))
+        } else {
+            Ok(None)
+        }
+    }
+    async fn store_allocated_port(
+        &self,
+        tunnel: &str,
+        server_address: &str,
+        port: u16,
+    ) -> Result<(), StorageError> {
+        let conn = self.connection.clone();
+        let conn = conn.lock().unwrap();
+        conn.execute(
+            "insert or replace into allocated_ports values (?1, ?2, ?3)",
+            (tunnel, server_address, port),
+        )?;
+        Ok(())
+    }
+    async fn acquire_lease(
+        &self,
+        name: &str,
+        holder: &str,
+        now: u64,
+        expires: u64,
+    ) -> Result<bool, StorageError> {
+        let conn = self.connection.clone();
+        let conn = conn.lock().unwrap();
+        let changed = conn.execute(ACQUIRE_LEASE, (name, holder, expires, now))?;
+        Ok(changed == 1)
+    }
+    async fn release_lease(&self, name: &str, holder: &str) -> Result<(), StorageError> {
+        let conn = self.connection.clone();
+        let conn = conn.lock().unwrap();
+        conn.execute(RELEASE_LEASE, (name, holder))?;
+        Ok(())
+    }
     async fn ensure(&self) -> Result<(), StorageError> {
         let conn = self.connection.clone();
         let conn = conn.lock().unwrap();
-        conn.execute(r#"
-            create table if not exists known_hosts(hostname varchar(255) primary key, fingerprint varchar(255) not null);
-        "#, ())?;
+        conn.execute(CREATE_KNOWN_HOSTS, ())?;
+        conn.execute(CREATE_ALLOCATED_PORTS, ())?;
+        conn.execute(CREATE_LEASES, ())?;
         Ok(())
     }
 }
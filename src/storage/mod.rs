@@ -17,8 +17,23 @@ pub(crate) mod rqlite;
 pub enum StorageError {
     #[error("sqlite returned an error: {1}")]
     LocalSqlite(rusqlite::Error, String),
+    #[error("rqlite returned an error: {0}")]
+    Rqlite(String),
 }
 
+/// takes the lease if it's free, expired or already held by the holder: it's a single
+/// statement, so that replicas racing for the lease can't both get it.
+/// Parameters: name, holder, expiration and current time (unix milliseconds)
+pub(crate) const ACQUIRE_LEASE: &str =
+    "insert into leases(name, holder, expires) values (?1, ?2, ?3)
+    on conflict(name) do update set holder = excluded.holder, expires = excluded.expires
+    where leases.holder = excluded.holder or leases.expires <= ?4";
+/// parameters: name, holder
+pub(crate) const RELEASE_LEASE: &str = "delete from leases where name = ?1 and holder = ?2";
+pub(crate) const CREATE_KNOWN_HOSTS: &str = "create table if not exists known_hosts(hostname varchar(255) primary key, fingerprint varchar(255) not null)";
+pub(crate) const CREATE_ALLOCATED_PORTS: &str = "create table if not exists allocated_ports(tunnel varchar(255) not null, hostname varchar(255) not null, port integer not null, primary key (tunnel, hostname))";
+pub(crate) const CREATE_LEASES: &str = "create table if not exists leases(name varchar(255) primary key, holder varchar(255) not null, expires integer not null)";
+
 #[cfg_attr(test, automock)]
 #[async_trait]
 pub(crate) trait Storage: Send + Sync {
@@ -28,17 +43,49 @@ pub(crate) trait Storage: Send + Sync {
         address: &str,
         fingerprint: &str,
     ) -> Result<(), StorageError>;
+    /// the remote port the server allocated to a tunnel the last time it was asked
+    /// for any free port
+    async fn get_allocated_port(
+        &self,
+        tunnel: &str,
+        server_address: &str,
+    ) -> Result<Option<u16>, StorageError>;
+    async fn store_allocated_port(
+        &self,
+        tunnel: &str,
+        server_address: &str,
+        port: u16,
+    ) -> Result<(), StorageError>;
+    /// takes or renews the lease `name` for `holder` until `expires`, unless another
+    /// holder has it past `now` (unix milliseconds): returns whether `holder` has it
+    async fn acquire_lease(
+        &self,
+        name: &str,
+        holder: &str,
+        now: u64,
+        expires: u64,
+    ) -> Result<bool, StorageError>;
+    /// gives the lease up, if `holder` still has it
+    async fn release_lease(&self, name: &str, holder: &str) -> Result<(), StorageError>;
     async fn ensure(&self) -> Result<(), StorageError>;
 }
 
-pub fn get_storage(storage_config: StorageConfig) -> Result<Box<dyn Storage>, TunnelError> {
+pub async fn get_storage(storage_config: StorageConfig) -> Result<Box<dyn Storage>, TunnelError> {
     match storage_config.storage_type {
         StorageType::Rqlite => {
             if let Some(config) = storage_config.rqlite {
+                let user = match config.user {
+                    Some(user) => Some(user.resolve().await?),
+                    None => None,
+                };
+                let password = match config.password {
+                    Some(password) => Some(password.resolve().await?),
+                    None => None,
+                };
                 Ok(Box::new(RqliteStorage::new(
-                    config.host.get(),
-                    config.user,
-                    config.password,
+                    &config.host.resolve().await?,
+                    user,
+                    password,
                 )?))
             } else {
                 Err(TunnelError::NoRqliteConfig)
@@ -1,11 +1,15 @@
-use std::sync::{Arc, Mutex};
-
 use async_trait::async_trait;
-use rqlite_rs::prelude::{RqliteClient, RqliteClientBuilder};
+use rqlite_rs::{
+    prelude::{RqliteClient, RqliteClientBuilder},
+    query,
+};
 
 use crate::tunneling::tunnel::TunnelError;
 
-use super::{Storage, StorageError};
+use super::{
+    ACQUIRE_LEASE, CREATE_ALLOCATED_PORTS, CREATE_KNOWN_HOSTS, CREATE_LEASES, RELEASE_LEASE,
+    Storage, StorageError,
+};
 
 pub struct RqliteStorage {
     client: RqliteClient,
@@ -33,16 +37,99 @@ impl RqliteStorage {
 #[async_trait]
 impl Storage for RqliteStorage {
     async fn get_server_fingerprint(&self, address: &str) -> Result<Option<String>, StorageError> {
-        Ok(Some(String::from("")))
+        let rows = self
+            .client
+            .fetch(query!(
+                "select fingerprint from known_hosts where hostname = ?",
+                address
+            )?)
+            .await?;
+        rows.first()
+            .map(|row| row.get::<String>("fingerprint"))
+            .transpose()
+            .map_err(|e| StorageError::Rqlite(e.to_string()))
     }
     async fn store_server_fingerprint(
         &self,
         address: &str,
         fingerprint: &str,
     ) -> Result<(), StorageError> {
+        tracing::info!("storing fingerprint for {:?}", address);
+        self.client
+            .exec(query!(
+                "insert into known_hosts values (?, ?)",
+                address,
+                fingerprint
+            )?)
+            .await?;
+        Ok(())
+    }
+    async fn get_allocated_port(
+        &self,
+        tunnel: &str,
+        server_address: &str,
+    ) -> Result<Option<u16>, StorageError> {
+        let rows = self
+            .client
+            .fetch(query!(
+                "select port from allocated_ports where tunnel = ? and hostname = ?",
+                tunnel,
+                server_address
+            )?)
+            .await?;
+        rows.first()
+            .map(|row| row.get::<u16>("port"))
+            .transpose()
+            .map_err(|e| StorageError::Rqlite(e.to_string()))
+    }
+    async fn store_allocated_port(
+        &self,
+        tunnel: &str,
+        server_address: &str,
+        port: u16,
+    ) -> Result<(), StorageError> {
+        self.client
+            .exec(query!(
+                "insert or replace into allocated_ports values (?, ?, ?)",
+                tunnel,
+                server_address,
+                port as i64
+            )?)
+            .await?;
+        Ok(())
+    }
+    async fn acquire_lease(
+        &self,
+        name: &str,
+        holder: &str,
+        now: u64,
+        expires: u64,
+    ) -> Result<bool, StorageError> {
+        let query = query!(ACQUIRE_LEASE, name, holder, expires as i64, now as i64)?;
+        let result = self.client.exec(query).await?;
+        Ok(result.rows_affected() == 1)
+    }
+    async fn release_lease(&self, name: &str, holder: &str) -> Result<(), StorageError> {
+        self.client
+            .exec(query!(RELEASE_LEASE, name, holder)?)
+            .await?;
         Ok(())
     }
     async fn ensure(&self) -> Result<(), StorageError> {
+        self.client.exec(query!(CREATE_KNOWN_HOSTS)?).await?;
+        self.client.exec(query!(CREATE_ALLOCATED_PORTS)?).await?;
+        self.client.exec(query!(CREATE_LEASES)?).await?;
         Ok(())
     }
 }
+
+impl From<rqlite_rs::error::QueryBuilderError> for StorageError {
+    fn from(value: rqlite_rs::error::QueryBuilderError) -> Self {
+        StorageError::Rqlite(value.to_string())
+    }
+}
+impl From<rqlite_rs::error::RequestError> for StorageError {
+    fn from(value: rqlite_rs::error::RequestError) -> Self {
+        StorageError::Rqlite(value.to_string())
+    }
+}
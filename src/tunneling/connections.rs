@@ -0,0 +1,380 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio::sync::{Notify, watch};
+
+/// where a forwarded connection is in its lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConnectionState {
+    /// the tunneled service is being connected
+    Connecting,
+    /// data is flowing in both directions
+    Open,
+    /// cancelled, it's being torn down
+    Cancelled,
+}
+impl Display for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = match self {
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Open => "open",
+            ConnectionState::Cancelled => "cancelled",
+        };
+        write!(f, "{state}")
+    }
+}
+
+/// a snapshot of a forwarded connection
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ConnectionInfo {
+    pub id: u64,
+    /// address and port of the client, as reported by the ssh server
+    pub originator: String,
+    pub started: SystemTime,
+    /// bytes received from the client
+    pub bytes_in: u64,
+    /// bytes sent back to the client
+    pub bytes_out: u64,
+    pub state: ConnectionState,
+}
+
+/// what went wrong with the connections of a tunnel so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct ConnectionStats {
+    /// connections the tunneled service couldn't be reached for
+    pub failures: u64,
+    /// connections refused because of the limits
+    pub rejections: u64,
+}
+
+/// the connections currently forwarded by a tunnel, shared by its sessions: cloning
+/// it gives access to the same connections
+#[derive(Clone)]
+pub(crate) struct ConnectionRegistry {
+    inner: Arc<Mutex<Registry>>,
+    stats: watch::Sender<ConnectionStats>,
+}
+impl Default for ConnectionRegistry {
+    fn default() -> Self {
+        ConnectionRegistry {
+            inner: Arc::default(),
+            stats: watch::Sender::new(ConnectionStats::default()),
+        }
+    }
+}
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    connections: HashMap<u64, Entry>,
+}
+struct Entry {
+    originator: String,
+    started: SystemTime,
+    state: ConnectionState,
+    counters: Arc<Counters>,
+    cancel: Arc<Notify>,
+}
+pub(crate) struct Counters {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    created: Instant,
+    /// milliseconds from `created` to the last transfer, in either direction
+    last_transfer: AtomicU64,
+}
+impl Default for Counters {
+    fn default() -> Self {
+        Counters {
+            bytes_in: AtomicU64::default(),
+            bytes_out: AtomicU64::default(),
+            created: Instant::now(),
+            last_transfer: AtomicU64::default(),
+        }
+    }
+}
+impl Counters {
+    pub fn received(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.transferred();
+    }
+    pub fn sent(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.transferred();
+    }
+    /// time since the last transfer, or since the connection was registered
+    pub fn idle_for(&self) -> Duration {
+        let last_transfer = Duration::from_millis(self.last_transfer.load(Ordering::Relaxed));
+        self.created.elapsed().saturating_sub(last_transfer)
+    }
+    fn transferred(&self) {
+        let elapsed = self.created.elapsed().as_millis() as u64;
+        self.last_transfer.fetch_max(elapsed, Ordering::Relaxed);
+    }
+}
+
+/// a registered connection, it's removed from the registry when dropped
+pub(crate) struct Connection {
+    id: u64,
+    registry: ConnectionRegistry,
+    counters: Arc<Counters>,
+    cancel: Arc<Notify>,
+}
+
+impl ConnectionRegistry {
+    /// registers a connection unless `limit` connections from the same address are
+    /// registered already, the refusal is then counted
+    pub fn register(&self, originator: String, limit: Option<usize>) -> Option<Connection> {
+        let mut registry = self.lock();
+        if let Some(limit) = limit {
+            let client = address(&originator);
+            let from_client = registry
+                .connections
+                .values()
+                .filter(|entry| address(&entry.originator) == client)
+                .count();
+            if from_client >= limit {
+                self.rejected();
+                return None;
+            }
+        }
+        let counters = Arc::new(Counters::default());
+        let cancel = Arc::new(Notify::new());
+        registry.next_id += 1;
+        let id = registry.next_id;
+        registry.connections.insert(
+            id,
+            Entry {
+                originator,
+                started: SystemTime::now(),
+                state: ConnectionState::Connecting,
+                counters: counters.clone(),
+                cancel: cancel.clone(),
+            },
+        );
+        Some(Connection {
+            id,
+            registry: self.clone(),
+            counters,
+            cancel,
+        })
+    }
+    /// every connection, oldest first
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<ConnectionInfo> = self
+            .lock()
+            .connections
+            .iter()
+            .map(|(id, entry)| ConnectionInfo {
+                id: *id,
+                originator: entry.originator.to_owned(),
+                started: entry.started,
+                bytes_in: entry.counters.bytes_in.load(Ordering::Relaxed),
+                bytes_out: entry.counters.bytes_out.load(Ordering::Relaxed),
+                state: entry.state,
+            })
+            .collect();
+        connections.sort_by_key(|c| c.id);
+        connections
+    }
+    pub fn len(&self) -> usize {
+        self.lock().connections.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// how many connections couldn't be forwarded to the tunneled service so far
+    pub fn failures(&self) -> u64 {
+        self.stats.borrow().failures
+    }
+    /// how many connections were refused because of the limits so far
+    pub fn rejections(&self) -> u64 {
+        self.stats.borrow().rejections
+    }
+    /// counts a connection refused because of the limits
+    pub fn rejected(&self) {
+        self.stats.send_modify(|stats| stats.rejections += 1);
+    }
+    /// observes the failures and rejections
+    pub fn stats(&self) -> watch::Receiver<ConnectionStats> {
+        self.stats.subscribe()
+    }
+    /// closes a single connection, returns whether it was found
+    pub fn cancel(&self, id: u64) -> bool {
+        match self.lock().connections.get_mut(&id) {
+            Some(entry) => {
+                entry.state = ConnectionState::Cancelled;
+                entry.cancel.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+    /// closes every connection, returns how many were cancelled
+    pub fn cancel_all(&self) -> usize {
+        let ids: Vec<u64> = self.lock().connections.keys().copied().collect();
+        ids.into_iter().filter(|id| self.cancel(*id)).count()
+    }
+    fn set_state(&self, id: u64, state: ConnectionState) {
+        if let Some(entry) = self.lock().connections.get_mut(&id) {
+            // a cancelled connection stays cancelled
+            if entry.state != ConnectionState::Cancelled {
+                entry.state = state;
+            }
+        }
+    }
+    fn lock(&self) -> std::sync::MutexGuard<'_, Registry> {
+        // the lock is never held across a panic-prone section, recover anyway
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// the address part of an `address:port` originator
+fn address(originator: &str) -> &str {
+    originator
+        .rsplit_once(':')
+        .map_or(originator, |(address, _)| address)
+}
+
+impl Connection {
+    pub fn counters(&self) -> Arc<Counters> {
+        self.counters.clone()
+    }
+    /// the tunneled service is connected
+    pub fn opened(&self) {
+        self.registry.set_state(self.id, ConnectionState::Open);
+    }
+    /// the tunneled service couldn't be reached
+    pub fn failed(&self) {
+        self.registry.stats.send_modify(|stats| stats.failures += 1);
+    }
+    /// resolves once the connection is cancelled through the registry
+    pub async fn cancelled(&self) {
+        self.cancel.notified().await
+    }
+}
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.registry.lock().connections.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_are_tracked_until_dropped() {
+        let registry = ConnectionRegistry::default();
+        let first = registry
+            .register(String::from("10.0.0.1:50000"), None)
+            .unwrap();
+        let second = registry
+            .register(String::from("10.0.0.2:50000"), None)
+            .unwrap();
+        first.opened();
+        first.counters().received(10);
+        first.counters().sent(32);
+
+        let connections = registry.list();
+        assert_eq!(connections.len(), 2);
+        assert_eq!(connections[0].originator, "10.0.0.1:50000");
+        assert_eq!(connections[0].state, ConnectionState::Open);
+        assert_eq!(
+            (connections[0].bytes_in, connections[0].bytes_out),
+            (10, 32)
+        );
+        assert_eq!(connections[1].state, ConnectionState::Connecting);
+
+        drop(first);
+        assert_eq!(registry.list()[0].originator, "10.0.0.2:50000");
+        second.failed();
+        drop(second);
+        assert!(registry.is_empty());
+        assert_eq!(registry.failures(), 1);
+    }
+
+    #[test]
+    fn transfers_reset_the_idle_time() {
+        let counters = Counters::default();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(counters.idle_for() >= Duration::from_millis(20));
+        counters.sent(1);
+        assert!(counters.idle_for() < Duration::from_millis(20));
+    }
+
+    #[test]
+    fn connections_are_limited_per_address() {
+        let registry = ConnectionRegistry::default();
+        let first = registry.register(String::from("10.0.0.1:50000"), Some(2));
+        let second = registry.register(String::from("10.0.0.1:50001"), Some(2));
+        assert!(first.is_some() && second.is_some());
+        assert!(
+            registry
+                .register(String::from("10.0.0.1:50002"), Some(2))
+                .is_none()
+        );
+        let other = registry.register(String::from("10.0.0.2:50000"), Some(2));
+        assert!(other.is_some());
+        assert_eq!(registry.rejections(), 1);
+
+        drop(first);
+        assert!(
+            registry
+                .register(String::from("10.0.0.1:50002"), Some(2))
+                .is_some()
+        );
+        registry.rejected();
+        assert_eq!(registry.rejections(), 2);
+        assert_eq!(
+            *registry.stats().borrow(),
+            ConnectionStats {
+                failures: 0,
+                rejections: 2
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn connections_are_cancelled_one_at_a_time() {
+        let registry = ConnectionRegistry::default();
+        let first = registry
+            .register(String::from("10.0.0.1:50000"), None)
+            .unwrap();
+        let second = registry
+            .register(String::from("10.0.0.2:50000"), None)
+            .unwrap();
+        first.opened();
+        second.opened();
+
+        let id = registry.list()[0].id;
+        assert!(registry.cancel(id));
+        assert!(!registry.cancel(id + 100));
+        first.cancelled().await;
+        let connections = registry.list();
+        assert_eq!(connections[0].state, ConnectionState::Cancelled);
+        assert_eq!(connections[1].originator, "10.0.0.2:50000");
+        assert_eq!(connections[1].state, ConnectionState::Open);
+        drop(second);
+    }
+
+    #[tokio::test]
+    async fn connections_can_be_cancelled() {
+        let registry = ConnectionRegistry::default();
+        let connection = registry
+            .register(String::from("10.0.0.1:50000"), None)
+            .unwrap();
+        assert_eq!(registry.cancel_all(), 1);
+        // the cancellation is not lost even if nobody was waiting yet
+        connection.cancelled().await;
+        connection.opened();
+        assert_eq!(registry.list()[0].state, ConnectionState::Cancelled);
+        drop(connection);
+        assert_eq!(registry.cancel_all(), 0);
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::watch;
+use tracing::warn;
+
+use crate::{config::LeaderElectionConfig, storage::Storage};
+
+use super::tunnel::TunnelError;
+
+const DEFAULT_LEASE_TTL: Duration = Duration::from_secs(15);
+
+/// a lease on a tunnel, kept in the storage shared by the replicas: only the replica
+/// holding it forwards the tunnel, the others stand by and take over once it expires
+pub(crate) struct LeaderElection {
+    storage: Arc<dyn Storage>,
+    /// named after the tunnel
+    lease: String,
+    holder: String,
+    ttl: Duration,
+    renew_interval: Duration,
+    /// when the lease runs out unless renewed, `None` when not held: the tunnel checks
+    /// it before forwarding
+    valid_until: watch::Sender<Option<Instant>>,
+    /// whether the leases table was created
+    ensured: bool,
+}
+impl LeaderElection {
+    pub fn new(
+        config: &LeaderElectionConfig,
+        storage: Arc<dyn Storage>,
+        lease: &str,
+    ) -> LeaderElection {
+        let ttl = config
+            .lease_ttl
+            .filter(|seconds| *seconds > 0)
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_LEASE_TTL);
+        LeaderElection {
+            storage,
+            lease: lease.to_owned(),
+            holder: config.holder.clone().unwrap_or_else(default_holder),
+            ttl,
+            renew_interval: config
+                .renew_interval
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs)
+                .unwrap_or(ttl / 3),
+            valid_until: watch::Sender::new(None),
+            ensured: false,
+        }
+    }
+    /// takes or renews the lease, returns whether it's held. On errors the lease is
+    /// considered held until it would have expired
+    pub async fn try_acquire(&mut self) -> Result<bool, TunnelError> {
+        if !self.ensured {
+            self.storage.ensure().await?;
+            self.ensured = true;
+        }
+        // counted from before asking, the storage may take a while to answer
+        let asked = Instant::now();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let held = self
+            .storage
+            .acquire_lease(
+                &self.lease,
+                &self.holder,
+                now.as_millis() as u64,
+                (now + self.ttl).as_millis() as u64,
+            )
+            .await?;
+        self.valid_until
+            .send_replace(held.then(|| asked + self.ttl));
+        Ok(held)
+    }
+    /// renews the lease, `Some` tells why it's not held anymore
+    pub async fn renew(&mut self) -> Option<String> {
+        match self.try_acquire().await {
+            Ok(true) => None,
+            Ok(false) => Some(String::from("the lease was taken by another replica")),
+            // the lease can't be confirmed in time, another replica may take it
+            Err(e) if !self.is_held_for(self.renew_interval) => {
+                Some(format!("cannot renew the lease: {e}"))
+            }
+            Err(e) => {
+                warn!(
+                    "cannot renew the lease of tunnel `{}`: {}",
+                    self.lease,
+                    e.to_string()
+                );
+                None
+            }
+        }
+    }
+    /// renews the lease every `renew_interval` until it's lost, returns why
+    pub async fn keep(&mut self) -> String {
+        loop {
+            tokio::time::sleep(self.renew_interval).await;
+            if let Some(reason) = self.renew().await {
+                return reason;
+            }
+        }
+    }
+    /// whether the lease is still held `after` from now, as far as this replica knows
+    pub fn is_held_for(&self, after: Duration) -> bool {
+        self.valid_until
+            .borrow()
+            .is_some_and(|until| Instant::now() + after < until)
+    }
+    /// when the lease runs out, as it's taken and renewed
+    pub fn valid_until(&self) -> watch::Receiver<Option<Instant>> {
+        self.valid_until.subscribe()
+    }
+    pub fn renew_interval(&self) -> Duration {
+        self.renew_interval
+    }
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+    /// gives the lease up, so that a standby replica can take over right away
+    pub async fn release(&mut self) {
+        if self.valid_until.send_replace(None).is_none() {
+            return;
+        }
+        if let Err(e) = self.storage.release_lease(&self.lease, &self.holder).await {
+            warn!(
+                "cannot release the lease of tunnel `{}`: {}",
+                self.lease,
+                e.to_string()
+            );
+        }
+    }
+}
+
+/// the hostname and the process id, unique among the replicas
+fn default_holder() -> String {
+    let hostname = std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|hostname| hostname.trim().to_owned())
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| String::from("tunglo"));
+    format!("{hostname}-{}", std::process::id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::local::LocalStorage;
+
+    fn election(storage: &Arc<dyn Storage>, holder: &str, ttl_ms: u64) -> LeaderElection {
+        let config = LeaderElectionConfig {
+            holder: Some(holder.to_owned()),
+            lease_ttl: None,
+            renew_interval: None,
+        };
+        LeaderElection {
+            ttl: Duration::from_millis(ttl_ms),
+            ..LeaderElection::new(&config, storage.clone(), "web")
+        }
+    }
+
+    #[tokio::test]
+    async fn a_single_replica_holds_the_lease() {
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::in_memory().unwrap());
+        let mut first = election(&storage, "first", 60_000);
+        let mut second = election(&storage, "second", 60_000);
+
+        assert!(first.try_acquire().await.unwrap());
+        assert!(!second.try_acquire().await.unwrap());
+        // renewing
+        assert!(first.try_acquire().await.unwrap());
+        assert!(first.is_held_for(Duration::from_secs(1)));
+        assert!(!second.is_held_for(Duration::ZERO));
+
+        first.release().await;
+        assert!(!first.is_held_for(Duration::ZERO));
+        assert!(second.try_acquire().await.unwrap());
+        assert!(!first.try_acquire().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn standbys_take_over_expired_leases() {
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::in_memory().unwrap());
+        let mut first = election(&storage, "first", 50);
+        let mut second = election(&storage, "second", 50);
+
+        assert!(first.try_acquire().await.unwrap());
+        assert!(!second.try_acquire().await.unwrap());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!first.is_held_for(Duration::ZERO));
+        assert!(second.try_acquire().await.unwrap());
+        assert!(!first.try_acquire().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn renewing_tells_when_the_lease_is_lost() {
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::in_memory().unwrap());
+        let mut first = election(&storage, "first", 50);
+        let mut second = election(&storage, "second", 50);
+        let valid_until = first.valid_until();
+
+        assert_eq!(first.renew().await, None);
+        assert!(valid_until.borrow().is_some());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(second.try_acquire().await.unwrap());
+        assert!(first.renew().await.is_some());
+        assert!(valid_until.borrow().is_none());
+    }
+}
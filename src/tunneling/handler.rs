@@ -1,45 +1,96 @@
 use crate::{
     config::StorageConfig,
-    storage::{self, Storage},
+    storage::{self, Storage, StorageError},
 };
 
-use super::{tunnel::TunnelError, tunnel_runner::TunnelRunner};
+use super::{
+    connections::ConnectionRegistry,
+    pool::Routes,
+    tunnel::{SessionClosed, TunnelError},
+    tunnel_runner::{ConnectionPolicy, Refused, TunnelRunner, enqueue},
+};
 use russh::{
     Channel,
-    client::{self, Handler},
+    client::{self, DisconnectReason, Handler},
+};
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
 };
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::{Sender, UnboundedSender};
 
+/// what every session shares with the tunnels forwarding ports on it
+#[derive(Clone)]
+pub(super) struct TunnelLink {
+    /// incoming connections are sent back to the tunnel through this
+    pub tx: Sender<(TunnelRunner, Channel<client::Msg>)>,
+    /// used to tell the tunnel when a session drops
+    pub disconnect_tx: UnboundedSender<SessionClosed>,
+    /// cleared when the tunnel shuts down, new forwarded channels are closed right away
+    pub accepting: Arc<AtomicBool>,
+    /// every forwarded connection is registered here until it's over
+    pub connections: ConnectionRegistry,
+    /// how the forwarded connections are handled
+    pub policy: ConnectionPolicy,
+    /// how many connections a single client address can have at once
+    pub max_per_originator: Option<usize>,
+}
 pub(super) struct ClientHandler {
-    tx: Sender<(TunnelRunner, Channel<client::Msg>)>,
-    to_addr: String,
-    to_port: u16,
+    /// the ports forwarded on this session, by tunnel
+    routes: Routes,
     /// these are needed for the server validation callback
     server_address: String,
     server_port: u16,
     storage: Box<dyn Storage>,
+    session_id: u64,
 }
 impl ClientHandler {
     pub async fn new(
-        to_addr: &str,
-        to_port: u16,
         server_address: &str,
         server_port: u16,
         storage_config: StorageConfig,
-        tx: Sender<(TunnelRunner, Channel<client::Msg>)>,
+        routes: Routes,
+        session_id: u64,
     ) -> Result<Self, TunnelError> {
-        let storage = storage::get_storage(storage_config)?;
+        let storage = storage::get_storage(storage_config).await?;
         storage.ensure().await?;
         Ok(ClientHandler {
-            tx,
-            to_addr: to_addr.to_string(),
-            to_port,
+            routes,
             server_address: server_address.to_string(),
             server_port,
             storage,
+            session_id,
         })
     }
 }
+impl ClientHandler {
+    /// bastions sharing an address (behind nat, ...) have a host key each
+    fn known_host(&self) -> String {
+        format!("{}:{}", self.server_address, self.server_port)
+    }
+    /// the fingerprint stored for `host`. Keys used to be stored by address alone,
+    /// such entries are taken over by port 22
+    async fn stored_fingerprint(&self, host: &str) -> Result<Option<String>, StorageError> {
+        let stored = self.storage.get_server_fingerprint(host).await?;
+        if stored.is_some() || self.server_port != 22 {
+            return Ok(stored);
+        }
+        let Some(legacy) = self
+            .storage
+            .get_server_fingerprint(&self.server_address)
+            .await?
+        else {
+            return Ok(None);
+        };
+        tracing::info!(
+            "moving the host key of {:?} to {:?}",
+            self.server_address,
+            host
+        );
+        self.storage.store_server_fingerprint(host, &legacy).await?;
+        Ok(Some(legacy))
+    }
+}
 impl Handler for ClientHandler {
     type Error = TunnelError;
 
@@ -59,29 +110,20 @@ impl Handler for ClientHandler {
             .fingerprint(Default::default())
             .to_string();
 
-        match self
-            .storage
-            .get_server_fingerprint(&self.server_address)
-            .await
-        {
+        let host = self.known_host();
+        match self.stored_fingerprint(&host).await {
             Ok(some_fingerprint) => {
                 if let Some(stored_fingerprint) = some_fingerprint {
                     // check the stored fingerprint against the one we are getting
                     if !server_fingerprint.eq(&stored_fingerprint) {
-                        tracing::error_span!("{:?} host key has changed!", self.server_address);
+                        tracing::error_span!("{:?} host key has changed!", host);
                         return Err(TunnelError::NastyKey);
                     }
-                    tracing::info!(
-                        "host key for {:?} matches the stored one",
-                        self.server_address
-                    );
+                    tracing::info!("host key for {:?} matches the stored one", host);
                 } else {
                     // tofu: store the key!
                     self.storage
-                        .store_server_fingerprint(
-                            &self.server_address,
-                            &server_fingerprint.to_string(),
-                        )
+                        .store_server_fingerprint(&host, &server_fingerprint.to_string())
                         .await?;
                 }
                 Ok(true)
@@ -92,20 +134,84 @@ impl Handler for ClientHandler {
             }
         }
     }
+    async fn disconnected(
+        &mut self,
+        reason: DisconnectReason<Self::Error>,
+    ) -> Result<(), Self::Error> {
+        let reason = match reason {
+            DisconnectReason::ReceivedDisconnect(info) => {
+                format!("the server closed the connection ({})", info.message)
+            }
+            DisconnectReason::Error(e) => e.to_string(),
+        };
+        // every tunnel forwarding on this session has to reconnect, the ones that are
+        // gone already have nobody to tell
+        for link in self.routes.links() {
+            let _ = link.disconnect_tx.send(SessionClosed {
+                session_id: self.session_id,
+                reason: reason.to_owned(),
+            });
+        }
+        Ok(())
+    }
     async fn server_channel_open_forwarded_tcpip(
         &mut self,
         channel: Channel<client::Msg>,
-        _connected_address: &str,
-        _connected_port: u32,
+        connected_address: &str,
+        connected_port: u32,
         _originator_address: &str,
         _originator_port: u32,
         _session: &mut client::Session,
     ) -> Result<(), Self::Error> {
-        let tunnel_runner = TunnelRunner::new(&self.to_addr, self.to_port)?;
-        tracing::info!("incoming connection: {_originator_address}:{_originator_port}");
-        self.tx.send((tunnel_runner, channel)).await.unwrap(); // send the runner back to the
-        // Tunnel instance
-
+        let Some(route) = self.routes.find(connected_address, connected_port) else {
+            tracing::warn!(
+                "refusing connection from {_originator_address}:{_originator_port}, no tunnel forwards {connected_address}:{connected_port}"
+            );
+            channel.close().await?;
+            return Ok(());
+        };
+        if !route.link.accepting.load(Ordering::SeqCst) {
+            tracing::info!(
+                "refusing connection from {_originator_address}:{_originator_port}, shutting down"
+            );
+            channel.close().await?;
+            return Ok(());
+        }
+        let Some(connection) = route.link.connections.register(
+            format!("{_originator_address}:{_originator_port}"),
+            route.link.max_per_originator,
+        ) else {
+            tracing::warn!(
+                "refusing connection from {_originator_address}:{_originator_port}, too many connections from {_originator_address}, {} refused so far",
+                route.link.connections.rejections()
+            );
+            route.link.policy.refuse(channel).await;
+            return Ok(());
+        };
+        let tunnel_runner =
+            TunnelRunner::new(&route.to_addr, route.to_port, connection, route.link.policy)?;
+        tracing::info!(
+            "incoming connection on {connected_address}:{connected_port}: {_originator_address}:{_originator_port}"
+        );
+        // the runner is sent back to the tunnel, the handler can't wait for room: it
+        // would hold every other session event back
+        match enqueue(
+            &route.link.tx,
+            tunnel_runner,
+            channel,
+            &route.link.connections,
+        )
+        .await
+        {
+            Ok(()) => {}
+            Err(Refused::QueueFull) => tracing::warn!(
+                "refused connection from {_originator_address}:{_originator_port}, too many connections waiting on {connected_address}:{connected_port}, {} refused so far",
+                route.link.connections.rejections()
+            ),
+            Err(Refused::TunnelGone) => tracing::info!(
+                "refused connection from {_originator_address}:{_originator_port}, the tunnel is gone"
+            ),
+        }
         Ok(())
     }
 }
@@ -115,7 +221,6 @@ mod tests {
 
     use russh::keys::PublicKey;
     use storage::MockStorage;
-    use tokio::sync::mpsc;
 
     use super::*;
     use mockall::predicate::*;
@@ -136,28 +241,26 @@ mod tests {
 
     #[tokio::test]
     async fn no_fingerprint_test() {
-        let (tx, _rx) = mpsc::channel(1);
         let public_key = create_public_key();
         let fingerprint = public_key.fingerprint(Default::default());
         let mut mock_storage = MockStorage::new();
         mock_storage
             .expect_get_server_fingerprint()
-            .with(eq("0.0.0.0"))
+            .with(eq("0.0.0.0:5050"))
             .times(1)
             .returning(|_| Ok(None)); // new host test
         mock_storage
             .expect_store_server_fingerprint()
-            .with(eq("0.0.0.0"), eq(fingerprint.to_string()))
+            .with(eq("0.0.0.0:5050"), eq(fingerprint.to_string()))
             .times(1)
             .returning(|_, __| Ok(()));
 
         let mut client_handler = ClientHandler {
-            tx,
-            to_addr: String::from("1.2.3.4"),
-            to_port: 8080,
+            routes: Routes::default(),
             server_address: String::from("0.0.0.0"),
             server_port: 5050,
             storage: Box::new(mock_storage),
+            session_id: 1,
         };
 
         let result = client_handler.check_server_key(&public_key).await;
@@ -167,13 +270,11 @@ mod tests {
 
     #[tokio::test]
     async fn nasty_key_test() {
-        let (tx, _rx) = mpsc::channel(1);
-
         let mut mock_storage = MockStorage::new();
         let nasty_key = nasty_public_key();
         mock_storage
             .expect_get_server_fingerprint()
-            .with(eq("0.0.0.0"))
+            .with(eq("0.0.0.0:5050"))
             .times(1)
             .returning(|_| {
                 let public_key = create_public_key();
@@ -181,12 +282,11 @@ mod tests {
                 Ok(Some(fingerprint.to_string()))
             });
         let mut client_handler = ClientHandler {
-            tx,
-            to_addr: String::from("1.2.3.4"),
-            to_port: 8080,
+            routes: Routes::default(),
             server_address: String::from("0.0.0.0"),
             server_port: 5050,
             storage: Box::new(mock_storage),
+            session_id: 1,
         };
 
         let result = client_handler.check_server_key(&nasty_key).await;
@@ -195,13 +295,11 @@ mod tests {
     }
     #[tokio::test]
     async fn ok_key_test() {
-        let (tx, _rx) = mpsc::channel(1);
-
         let mut mock_storage = MockStorage::new();
         let nasty_key = create_public_key();
         mock_storage
             .expect_get_server_fingerprint()
-            .with(eq("0.0.0.0"))
+            .with(eq("0.0.0.0:5050"))
             .times(1)
             .returning(|_| {
                 let public_key = create_public_key();
@@ -209,16 +307,72 @@ mod tests {
                 Ok(Some(fingerprint.to_string()))
             });
         let mut client_handler = ClientHandler {
-            tx,
-            to_addr: String::from("1.2.3.4"),
-            to_port: 8080,
+            routes: Routes::default(),
             server_address: String::from("0.0.0.0"),
             server_port: 5050,
             storage: Box::new(mock_storage),
+            session_id: 1,
         };
 
         let result = client_handler.check_server_key(&nasty_key).await;
         assert!(result.is_ok());
         assert!(result.ok().unwrap());
     }
+
+    #[tokio::test]
+    async fn bastions_on_the_same_address_have_their_own_key() {
+        let fingerprint = create_public_key().fingerprint(Default::default());
+        let mut mock_storage = MockStorage::new();
+        mock_storage
+            .expect_get_server_fingerprint()
+            .with(eq("1.2.3.4:2222"))
+            .times(1)
+            .returning(|_| Ok(None));
+        mock_storage
+            .expect_store_server_fingerprint()
+            .with(eq("1.2.3.4:2222"), eq(fingerprint.to_string()))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut client_handler = ClientHandler {
+            routes: Routes::default(),
+            server_address: String::from("1.2.3.4"),
+            server_port: 2222,
+            storage: Box::new(mock_storage),
+            session_id: 1,
+        };
+
+        let result = client_handler.check_server_key(&create_public_key()).await;
+        assert!(result.is_ok());
+    }
+    #[tokio::test]
+    async fn keys_stored_by_address_are_moved_to_port_22() {
+        let fingerprint = create_public_key().fingerprint(Default::default());
+        let stored = fingerprint.to_string();
+        let mut mock_storage = MockStorage::new();
+        mock_storage
+            .expect_get_server_fingerprint()
+            .with(eq("1.2.3.4:22"))
+            .times(1)
+            .returning(|_| Ok(None));
+        mock_storage
+            .expect_get_server_fingerprint()
+            .with(eq("1.2.3.4"))
+            .times(1)
+            .returning(move |_| Ok(Some(stored.to_owned())));
+        mock_storage
+            .expect_store_server_fingerprint()
+            .with(eq("1.2.3.4:22"), eq(fingerprint.to_string()))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut client_handler = ClientHandler {
+            routes: Routes::default(),
+            server_address: String::from("1.2.3.4"),
+            server_port: 22,
+            storage: Box::new(mock_storage),
+            session_id: 1,
+        };
+
+        let result = client_handler.check_server_key(&nasty_public_key()).await;
+        assert!(matches!(result, Err(TunnelError::NastyKey)));
+    }
 }
@@ -0,0 +1,506 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use futures::future::{select_all, try_join_all};
+use tokio::{
+    sync::{Semaphore, watch},
+    task::JoinHandle,
+};
+use tracing::{error, info};
+
+use crate::{
+    config::{LeaderElectionConfig, StorageConfig, StorageType, TungloConfig, TunnelConfig},
+    storage::{self, Storage},
+};
+
+use super::{
+    connections::ConnectionRegistry,
+    election::LeaderElection,
+    pool::SessionPool,
+    state::{StateTracker, TunnelState, TunnelStatus},
+    supervisor::TunnelSupervisor,
+    tunnel::{Tunnel, TunnelError},
+};
+
+/// owns the supervisors of every configured tunnel, and applies new configurations
+/// touching only the tunnels that actually changed
+pub(crate) struct TunnelManager {
+    storage_config: Option<StorageConfig>,
+    drain_timeout: Duration,
+    connect_concurrency: Option<usize>,
+    /// shared by every tunnel, it's replaced when `connect_concurrency` changes
+    connect_limit: Option<Arc<Semaphore>>,
+    /// tunnels to the same bastion share their ssh session through it
+    pool: SessionPool,
+    leader_election: Option<LeaderElectionConfig>,
+    /// where the leases are kept, when leader election is enabled
+    leases: Option<Arc<dyn Storage>>,
+    tunnels: HashMap<String, RunningTunnel>,
+    /// told to shut down, and not done draining yet
+    stopping: Vec<(String, RunningTunnel)>,
+}
+struct RunningTunnel {
+    config: TunnelConfig,
+    /// shared by the members
+    shutdown_tx: watch::Sender<bool>,
+    /// a single one, or one per bastion in active mode
+    members: Vec<Member>,
+}
+/// a supervised tunnel, reconnected independently of the other members
+struct Member {
+    status: watch::Receiver<TunnelStatus>,
+    connections: ConnectionRegistry,
+    handle: JoinHandle<Result<(), TunnelError>>,
+}
+impl RunningTunnel {
+    fn is_alive(&self) -> bool {
+        self.members.iter().all(|m| !m.handle.is_finished())
+    }
+}
+/// what applying a configuration changes, tunnels are identified by name
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ReloadPlan {
+    pub start: Vec<String>,
+    pub stop: Vec<String>,
+    pub restart: Vec<String>,
+    /// tunnels that couldn't be created, they are not part of the other lists
+    pub failed: Vec<String>,
+}
+
+impl ReloadPlan {
+    /// `running` holds the configuration of the current tunnels, and whether their
+    /// supervisor is still alive: dead tunnels are restarted even if unchanged
+    fn new(
+        running: &HashMap<&str, (&TunnelConfig, bool)>,
+        storage_changed: bool,
+        tunnels: &[TunnelConfig],
+    ) -> ReloadPlan {
+        let mut plan = ReloadPlan::default();
+        for config in tunnels {
+            match running.get(config.name.as_str()) {
+                None => plan.start.push(config.name.to_owned()),
+                Some((running_config, alive)) => {
+                    if storage_changed || *running_config != config || !alive {
+                        plan.restart.push(config.name.to_owned());
+                    }
+                }
+            }
+        }
+        plan.stop = running
+            .keys()
+            .filter(|name| !tunnels.iter().any(|c| c.name == **name))
+            .map(|name| name.to_string())
+            .collect();
+        plan.stop.sort();
+        plan
+    }
+    pub fn is_empty(&self) -> bool {
+        self.start.is_empty()
+            && self.stop.is_empty()
+            && self.restart.is_empty()
+            && self.failed.is_empty()
+    }
+}
+
+impl TunnelManager {
+    pub fn new(drain_timeout: Duration) -> TunnelManager {
+        TunnelManager {
+            storage_config: None,
+            drain_timeout,
+            connect_concurrency: None,
+            connect_limit: None,
+            pool: SessionPool::default(),
+            leader_election: None,
+            leases: None,
+            tunnels: HashMap::new(),
+            stopping: vec![],
+        }
+    }
+    /// applies `config`: every new or changed tunnel is created before anything is
+    /// stopped, so an invalid configuration leaves the running tunnels untouched.
+    /// A tunnel that cannot be created doesn't affect the others: a new one is
+    /// reported as failed, a changed one keeps running with its old configuration
+    pub async fn apply(
+        &mut self,
+        config: TungloConfig,
+        drain_timeout: Duration,
+    ) -> Result<ReloadPlan, TunnelError> {
+        let mut names: Vec<&str> = config.tunnels.iter().map(|c| c.name.as_str()).collect();
+        names.sort();
+        if let Some(duplicate) = names.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(TunnelError::InvalidConfig(format!(
+                "tunnel name `{}` is used more than once",
+                duplicate[0]
+            )));
+        }
+
+        // a local database isn't shared, every replica would hold every lease
+        if config.leader_election.is_some() && config.storage.storage_type == StorageType::Local {
+            return Err(TunnelError::InvalidConfig(String::from(
+                "leader election needs a storage shared by the replicas, not `local`",
+            )));
+        }
+
+        // leases live in the storage, tunnels are elected again when either changes
+        let storage_changed = self.storage_config.as_ref() != Some(&config.storage)
+            || self.leader_election != config.leader_election;
+        let leases: Option<Arc<dyn Storage>> = match &config.leader_election {
+            Some(_) => Some(Arc::from(
+                storage::get_storage(config.storage.clone()).await?,
+            )),
+            None => None,
+        };
+        let running: HashMap<&str, (&TunnelConfig, bool)> = self
+            .tunnels
+            .iter()
+            .map(|(name, t)| (name.as_str(), (&t.config, t.is_alive())))
+            .collect();
+        let mut plan = ReloadPlan::new(&running, storage_changed, &config.tunnels);
+
+        let mut tunnels = vec![];
+        let mut failed = vec![];
+        for tunnel_config in config
+            .tunnels
+            .into_iter()
+            .filter(|c| plan.start.contains(&c.name) || plan.restart.contains(&c.name))
+        {
+            let members = try_join_all(
+                tunnel_config
+                    .members()
+                    .into_iter()
+                    .map(|member| Tunnel::new(member, config.storage.clone(), self.pool.clone())),
+            )
+            .await;
+            match members {
+                Ok(members) => tunnels.push((tunnel_config, members)),
+                Err(e) => {
+                    error!(
+                        "cannot create tunnel `{}`: {}",
+                        tunnel_config.name,
+                        e.to_string()
+                    );
+                    plan.failed.push(tunnel_config.name.to_owned());
+                    failed.push((tunnel_config, e));
+                }
+            }
+        }
+        plan.restart.retain(|name| !plan.failed.contains(name));
+        plan.start.retain(|name| !plan.failed.contains(name));
+
+        // the old tunnels must release their remote port before the new ones start
+        self.stop(plan.stop.iter().chain(plan.restart.iter())).await;
+        self.drain_timeout = drain_timeout;
+        self.storage_config = Some(config.storage);
+        if storage_changed {
+            self.leader_election = config.leader_election;
+            self.leases = leases;
+        }
+        if self.connect_concurrency != config.connect_concurrency {
+            // tunnels already running keep the limit they were started with
+            self.connect_concurrency = config.connect_concurrency;
+            self.connect_limit = config
+                .connect_concurrency
+                .map(|permits| Arc::new(Semaphore::new(permits.max(1))));
+        }
+        for (tunnel_config, members) in tunnels {
+            self.spawn(tunnel_config, members);
+        }
+        for (tunnel_config, e) in failed {
+            if !self.tunnels.contains_key(&tunnel_config.name) {
+                self.insert_failed(tunnel_config, e);
+            }
+        }
+        Ok(plan)
+    }
+    /// keeps track of a tunnel that couldn't even be created, so that it's reported
+    /// like the others and retried on the next reload
+    fn insert_failed(&mut self, config: TunnelConfig, error: TunnelError) {
+        let state = StateTracker::new(&config.name);
+        state.fail(TunnelState::Failed, &error);
+        let (shutdown_tx, _) = watch::channel(false);
+        self.tunnels.insert(
+            config.name.to_owned(),
+            RunningTunnel {
+                config,
+                shutdown_tx,
+                members: vec![Member {
+                    status: state.subscribe(),
+                    connections: ConnectionRegistry::default(),
+                    handle: tokio::spawn(async move { Err(error) }),
+                }],
+            },
+        );
+    }
+    fn spawn(&mut self, config: TunnelConfig, tunnels: Vec<Tunnel>) {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let members = tunnels
+            .into_iter()
+            .map(|tunnel| {
+                let status = tunnel.status();
+                let connections = tunnel.connections();
+                let election =
+                    self.leader_election.as_ref().zip(self.leases.clone()).map(
+                        |(election, leases)| LeaderElection::new(election, leases, tunnel.name()),
+                    );
+                let supervisor = TunnelSupervisor::new(
+                    tunnel,
+                    config.reconnect.clone().unwrap_or_default(),
+                    shutdown_rx.clone(),
+                    self.drain_timeout,
+                    self.connect_limit.clone(),
+                    election,
+                );
+                Member {
+                    status,
+                    connections,
+                    handle: tokio::spawn(supervisor.run()),
+                }
+            })
+            .collect();
+        self.tunnels.insert(
+            config.name.to_owned(),
+            RunningTunnel {
+                config,
+                shutdown_tx,
+                members,
+            },
+        );
+    }
+    /// stops the given tunnels concurrently, waiting for them to drain. When this is
+    /// cancelled the tunnels keep draining, and `shutdown` still waits for them
+    async fn stop<'a>(&mut self, names: impl Iterator<Item = &'a String>) {
+        for (name, tunnel) in names
+            .filter_map(|name| self.tunnels.remove_entry(name))
+            .collect::<Vec<_>>()
+        {
+            let _ = tunnel.shutdown_tx.send(true);
+            self.stopping.push((name, tunnel));
+        }
+        while let Some((name, tunnel)) = self.stopping.last_mut() {
+            // removed only once finished, a handle can't be awaited again
+            while let Some(member) = tunnel.members.last_mut() {
+                if let Err(e) = (&mut member.handle).await {
+                    error!("tunnel `{name}` panicked: {}", e.to_string());
+                }
+                tunnel.members.pop();
+            }
+            info!("tunnel `{name}` stopped");
+            self.stopping.pop();
+        }
+    }
+    /// the current status of every tunnel, sorted by name: tunnels in active mode
+    /// report one per bastion
+    pub fn statuses(&self) -> Vec<TunnelStatus> {
+        let mut statuses: Vec<TunnelStatus> =
+            self.members().map(|m| m.status.borrow().clone()).collect();
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+        statuses
+    }
+    /// observes the lifecycle of every tunnel
+    pub fn subscribe(&self) -> Vec<watch::Receiver<TunnelStatus>> {
+        self.members().map(|m| m.status.clone()).collect()
+    }
+    fn members(&self) -> impl Iterator<Item = &Member> {
+        self.tunnels.values().flat_map(|t| t.members.iter())
+    }
+    /// resolves once every tunnel has failed for good: nothing is forwarded, and
+    /// nothing will be until the configuration changes
+    pub async fn all_failed(&self) {
+        let mut statuses = self.subscribe();
+        loop {
+            if !statuses.is_empty()
+                && statuses
+                    .iter()
+                    .all(|s| s.borrow().state == TunnelState::Failed)
+            {
+                return;
+            }
+            // the status of a finished tunnel can't change anymore
+            let open: Vec<&mut watch::Receiver<TunnelStatus>> = statuses
+                .iter_mut()
+                .filter(|s| s.has_changed().is_ok())
+                .collect();
+            if open.is_empty() {
+                return std::future::pending().await;
+            }
+            select_all(open.into_iter().map(|s| Box::pin(s.changed()))).await;
+        }
+    }
+    /// the connections forwarded by every tunnel, sorted by name: tunnels in active
+    /// mode have a registry per bastion, named like their status
+    pub fn connections(&self) -> Vec<(String, ConnectionRegistry)> {
+        let mut connections: Vec<(String, ConnectionRegistry)> = self
+            .members()
+            .map(|m| (m.status.borrow().name.to_owned(), m.connections.clone()))
+            .collect();
+        connections.sort_by(|a, b| a.0.cmp(&b.0));
+        connections
+    }
+    pub fn drain_timeout(&self) -> Duration {
+        self.drain_timeout
+    }
+    /// stops every tunnel
+    pub async fn shutdown(&mut self) {
+        let names: Vec<String> = self.tunnels.keys().cloned().collect();
+        self.stop(names.iter()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tunnel(name: &str, remote_interface_port: u16) -> TunnelConfig {
+        let config: TungloConfig = toml::from_str(&format!(
+            r#"
+            [storage]
+            type = "local"
+            [[tunnels]]
+            name = "{name}"
+            remote_ssh_address = "1.1.1.1"
+            remote_ssh_port = 123
+            remote_ssh_user = "macca"
+            private_key_path = "path"
+            remote_interface_address = "1.0.0.0"
+            remote_interface_port = {remote_interface_port}
+            to_address = "localhost"
+            to_port = 8082
+            type = "http"
+        "#
+        ))
+        .unwrap();
+        config.tunnels.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn reload_plan_diffs_tunnels_by_name() {
+        let unchanged = tunnel("unchanged", 9000);
+        let changed = tunnel("changed", 9001);
+        let removed = tunnel("removed", 9002);
+        let running = HashMap::from([
+            ("unchanged", (&unchanged, true)),
+            ("changed", (&changed, true)),
+            ("removed", (&removed, true)),
+        ]);
+        let new_tunnels = vec![
+            tunnel("unchanged", 9000),
+            tunnel("changed", 9091),
+            tunnel("added", 9003),
+        ];
+
+        let plan = ReloadPlan::new(&running, false, &new_tunnels);
+        assert_eq!(
+            plan,
+            ReloadPlan {
+                start: vec![String::from("added")],
+                stop: vec![String::from("removed")],
+                restart: vec![String::from("changed")],
+                failed: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn reload_plan_restarts_dead_tunnels_and_storage_changes() {
+        let first = tunnel("first", 9000);
+        let second = tunnel("second", 9001);
+        let running = HashMap::from([("first", (&first, false)), ("second", (&second, true))]);
+        let new_tunnels = vec![tunnel("first", 9000), tunnel("second", 9001)];
+
+        let plan = ReloadPlan::new(&running, false, &new_tunnels);
+        assert_eq!(plan.restart, vec![String::from("first")]);
+
+        let plan = ReloadPlan::new(&running, true, &new_tunnels);
+        assert_eq!(
+            plan.restart,
+            vec![String::from("first"), String::from("second")]
+        );
+        assert!(plan.start.is_empty() && plan.stop.is_empty());
+        assert_eq!(
+            ReloadPlan::new(&running, false, &[tunnel("first", 9000)]).stop,
+            vec![String::from("second")]
+        );
+    }
+
+    #[tokio::test]
+    async fn leader_election_needs_a_shared_storage() {
+        let config: TungloConfig = toml::from_str(
+            r#"
+            tunnels = []
+            [leader_election]
+            [storage]
+            type = "local"
+        "#,
+        )
+        .unwrap();
+        let mut manager = TunnelManager::new(Duration::from_secs(1));
+        assert!(matches!(
+            manager.apply(config, Duration::from_secs(1)).await,
+            Err(TunnelError::InvalidConfig(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn connections_are_listed_per_member() {
+        let mut manager = TunnelManager::new(Duration::from_secs(1));
+        let (shutdown_tx, _) = watch::channel(false);
+        let members = ["web@1.1.1.2:22", "web@1.1.1.1:22"]
+            .into_iter()
+            .map(|name| Member {
+                status: StateTracker::new(name).subscribe(),
+                connections: ConnectionRegistry::default(),
+                handle: tokio::spawn(async { Ok(()) }),
+            })
+            .collect();
+        manager.tunnels.insert(
+            String::from("web"),
+            RunningTunnel {
+                config: tunnel("web", 9000),
+                shutdown_tx,
+                members,
+            },
+        );
+
+        let names: Vec<String> = manager
+            .connections()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["web@1.1.1.1:22", "web@1.1.1.2:22"]);
+    }
+
+    #[tokio::test]
+    async fn stopped_tunnels_keep_draining_when_interrupted() {
+        let mut manager = TunnelManager::new(Duration::from_secs(1));
+        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+        let handle = tokio::spawn(async move {
+            let _ = shutdown_rx.changed().await;
+            // draining
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(())
+        });
+        manager.tunnels.insert(
+            String::from("web"),
+            RunningTunnel {
+                config: tunnel("web", 9000),
+                shutdown_tx,
+                members: vec![Member {
+                    status: StateTracker::new("web").subscribe(),
+                    connections: ConnectionRegistry::default(),
+                    handle,
+                }],
+            },
+        );
+
+        let names = [String::from("web")];
+        let stop = manager.stop(names.iter());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), stop)
+                .await
+                .is_err()
+        );
+        assert!(manager.tunnels.is_empty());
+        assert_eq!(manager.stopping.len(), 1);
+        manager.shutdown().await;
+        assert!(manager.stopping.is_empty());
+    }
+}
@@ -1,3 +1,10 @@
+pub(crate) mod auth;
+pub(crate) mod connections;
+pub(crate) mod election;
 pub(crate) mod handler;
+pub(crate) mod manager;
+pub(crate) mod pool;
+pub(crate) mod state;
+pub(crate) mod supervisor;
 pub(crate) mod tunnel;
 pub(crate) mod tunnel_runner;
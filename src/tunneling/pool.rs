@@ -0,0 +1,208 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use russh::client::Handle;
+
+use crate::config::{BastionConfig, TunnelConfig};
+
+use super::handler::{ClientHandler, TunnelLink};
+
+/// what makes tunnels able to share an ssh session: same server, same user, same
+/// credentials and same session settings
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct BastionKey {
+    address: String,
+    port: u16,
+    user: String,
+    /// revision of the resolved credentials, tunnels whose keys differ get their own
+    /// session even if they log in as the same user
+    credentials: u64,
+    keepalive_interval: Option<u64>,
+    keepalive_max: Option<usize>,
+    inactivity_timeout: Option<u64>,
+}
+impl BastionKey {
+    pub fn new(config: &TunnelConfig, bastion: &BastionConfig) -> BastionKey {
+        BastionKey {
+            address: bastion.address.to_owned(),
+            port: bastion.port,
+            user: config.remote_ssh_user.to_owned(),
+            credentials: 0,
+            keepalive_interval: config.keepalive_interval,
+            keepalive_max: config.keepalive_max,
+            inactivity_timeout: config.inactivity_timeout,
+        }
+    }
+    /// the key for a given revision of the credentials
+    pub fn with_credentials(&self, revision: u64) -> BastionKey {
+        BastionKey {
+            credentials: revision,
+            ..self.clone()
+        }
+    }
+}
+
+/// where the channels opened for a forwarded remote port go
+#[derive(Clone)]
+pub(super) struct Route {
+    pub address: String,
+    pub port: u32,
+    pub to_addr: String,
+    pub to_port: u16,
+    pub link: TunnelLink,
+}
+/// the remote ports forwarded on a session, by the tunnels sharing it
+#[derive(Clone, Default)]
+pub(super) struct Routes(Arc<Mutex<Vec<Route>>>);
+impl Routes {
+    pub fn add(&self, route: Route) {
+        self.lock().push(route);
+    }
+    pub fn remove(&self, address: &str, port: u32) {
+        self.lock()
+            .retain(|route| route.address != address || route.port != port);
+    }
+    /// the route of the port a channel was opened for: the server may report the
+    /// bound address differently than it was requested (e.g. `localhost`), so the
+    /// port alone is enough when no address matches
+    pub fn find(&self, connected_address: &str, connected_port: u32) -> Option<Route> {
+        let routes = self.lock();
+        routes
+            .iter()
+            .find(|r| r.port == connected_port && r.address == connected_address)
+            .or_else(|| routes.iter().find(|r| r.port == connected_port))
+            .cloned()
+    }
+    pub fn links(&self) -> Vec<TunnelLink> {
+        self.lock().iter().map(|r| r.link.clone()).collect()
+    }
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Route>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// an authenticated ssh session, possibly forwarding ports for several tunnels: it's
+/// dropped, and so closed, once no tunnel uses it anymore
+pub(super) struct SharedSession {
+    pub id: u64,
+    pub handle: Handle<ClientHandler>,
+    pub routes: Routes,
+}
+
+/// the sessions currently open, by bastion: a slot is locked by the tunnel opening
+/// its session, so that the other tunnels wait and reuse it
+pub(super) type Slot = Arc<tokio::sync::Mutex<Weak<SharedSession>>>;
+#[derive(Clone, Default)]
+pub(crate) struct SessionPool {
+    slots: Arc<Mutex<HashMap<BastionKey, Slot>>>,
+    /// session ids are unique across tunnels, since sessions are shared
+    sessions_opened: Arc<AtomicU64>,
+}
+impl SessionPool {
+    pub(super) fn slot(&self, key: BastionKey) -> Slot {
+        let mut slots = self.slots.lock().unwrap_or_else(|e| e.into_inner());
+        // forget the bastions nobody is connected or connecting to
+        slots.retain(|_, slot| {
+            Arc::strong_count(slot) > 1
+                || slot
+                    .try_lock()
+                    .map(|session| session.strong_count() > 0)
+                    .unwrap_or(true)
+        });
+        slots.entry(key).or_default().clone()
+    }
+    pub(super) fn next_session_id(&self) -> u64 {
+        self.sessions_opened.fetch_add(1, Ordering::SeqCst) + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::tunneling::connections::ConnectionRegistry;
+
+    fn route(address: &str, port: u32, to_port: u16) -> Route {
+        let (tx, _rx) = mpsc::channel(1);
+        let (disconnect_tx, _disconnect_rx) = mpsc::unbounded_channel();
+        Route {
+            address: address.to_owned(),
+            port,
+            to_addr: String::from("localhost"),
+            to_port,
+            link: TunnelLink {
+                tx,
+                disconnect_tx,
+                accepting: Arc::new(AtomicBool::new(true)),
+                connections: ConnectionRegistry::default(),
+                policy: Default::default(),
+                max_per_originator: None,
+            },
+        }
+    }
+
+    #[test]
+    fn channels_are_routed_by_connected_port() {
+        let routes = Routes::default();
+        routes.add(route("0.0.0.0", 9000, 8080));
+        routes.add(route("127.0.0.1", 9001, 8081));
+        routes.add(route("10.0.0.1", 9001, 8082));
+
+        assert_eq!(routes.find("0.0.0.0", 9000).map(|r| r.to_port), Some(8080));
+        assert_eq!(routes.find("10.0.0.1", 9001).map(|r| r.to_port), Some(8082));
+        assert_eq!(
+            routes.find("localhost", 9001).map(|r| r.to_port),
+            Some(8081)
+        );
+        assert!(routes.find("0.0.0.0", 9002).is_none());
+
+        routes.remove("0.0.0.0", 9000);
+        assert!(routes.find("0.0.0.0", 9000).is_none());
+        assert_eq!(routes.links().len(), 2);
+    }
+
+    #[test]
+    fn slots_are_shared_by_bastion() {
+        let pool = SessionPool::default();
+        let config: crate::config::TungloConfig = toml::from_str(
+            r#"
+            [storage]
+            type = "local"
+            [[tunnels]]
+            name = "first"
+            remote_ssh_address = "1.1.1.1"
+            remote_ssh_port = 22
+            remote_ssh_user = "macca"
+            private_key_path = "path"
+            remote_interface_address = "0.0.0.0"
+            remote_interface_port = 9000
+            to_address = "localhost"
+            to_port = 8080
+            type = "http"
+        "#,
+        )
+        .unwrap();
+        let tunnel = &config.tunnels[0];
+        let bastion = BastionKey::new(tunnel, &tunnel.bastions()[0]);
+        let slot = pool.slot(bastion.with_credentials(1));
+        assert!(Arc::ptr_eq(&slot, &pool.slot(bastion.with_credentials(1))));
+        assert!(!Arc::ptr_eq(&slot, &pool.slot(bastion.with_credentials(2))));
+        let fallback = BastionConfig {
+            address: String::from("2.2.2.2"),
+            port: 22,
+        };
+        assert!(!Arc::ptr_eq(
+            &slot,
+            &pool.slot(BastionKey::new(tunnel, &fallback).with_credentials(1))
+        ));
+        assert_ne!(pool.next_session_id(), pool.next_session_id());
+    }
+}
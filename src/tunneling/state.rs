@@ -0,0 +1,310 @@
+use std::{fmt::Display, time::SystemTime};
+
+use futures::future::join_all;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use super::connections::ConnectionStats;
+
+/// where a tunnel is in its lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TunnelState {
+    /// created, never connected
+    Idle,
+    /// opening the tcp connection and doing the ssh handshake
+    Connecting,
+    /// authenticating and asking the server to forward the remote port
+    Authenticating,
+    /// the remote port is forwarded, connections are being served
+    Forwarding,
+    /// waiting before the next connection attempt
+    BackingOff,
+    /// another replica holds the tunnel's lease, waiting to take over
+    Standby,
+    /// draining the open connections before stopping
+    Stopping,
+    Stopped,
+    /// gave up after a fatal error or after running out of attempts
+    Failed,
+}
+impl TunnelState {
+    pub fn is_terminal(self) -> bool {
+        matches!(self, TunnelState::Stopped | TunnelState::Failed)
+    }
+    /// whether the first connection attempt is still going on
+    pub fn is_starting(self) -> bool {
+        matches!(
+            self,
+            TunnelState::Idle | TunnelState::Connecting | TunnelState::Authenticating
+        )
+    }
+    pub fn can_transition_to(self, next: TunnelState) -> bool {
+        use TunnelState::*;
+        match (self, next) {
+            (Stopped | Failed, _) => false,
+            (Stopping, next) => next == Stopped,
+            (_, Stopping | Failed | BackingOff | Standby) => true,
+            (Idle | BackingOff | Standby, Connecting) => true,
+            (Connecting, Authenticating) => true,
+            // rejected by a bastion, trying the next one
+            (Authenticating, Connecting) => true,
+            (Authenticating, Forwarding) => true,
+            _ => false,
+        }
+    }
+}
+impl Display for TunnelState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = match self {
+            TunnelState::Idle => "idle",
+            TunnelState::Connecting => "connecting",
+            TunnelState::Authenticating => "authenticating",
+            TunnelState::Forwarding => "forwarding",
+            TunnelState::BackingOff => "backing off",
+            TunnelState::Standby => "standby",
+            TunnelState::Stopping => "stopping",
+            TunnelState::Stopped => "stopped",
+            TunnelState::Failed => "failed",
+        };
+        write!(f, "{state}")
+    }
+}
+
+/// a snapshot of a tunnel's lifecycle, as published to observers
+#[derive(Debug, Clone)]
+pub(crate) struct TunnelStatus {
+    pub name: String,
+    pub state: TunnelState,
+    /// when the tunnel entered `state`
+    pub since: SystemTime,
+    /// the error that caused the last failed attempt or disconnection, it is kept
+    /// after the tunnel recovers
+    pub last_error: Option<String>,
+    /// the remote ports currently forwarded, useful when allocated by the server
+    pub remote_ports: Vec<u16>,
+    /// the bastion (`address:port`) the tunnel goes through, when forwarding
+    pub bastion: Option<String>,
+    /// connections that failed or were refused since the tunnel started
+    pub connections: ConnectionStats,
+}
+
+/// owned by the task driving the tunnel, which is the only one allowed to change its
+/// state: everybody else observes it through `subscribe`
+pub(crate) struct StateTracker {
+    tx: watch::Sender<TunnelStatus>,
+}
+impl StateTracker {
+    pub fn new(name: &str) -> StateTracker {
+        let (tx, _) = watch::channel(TunnelStatus {
+            name: name.to_owned(),
+            state: TunnelState::Idle,
+            since: SystemTime::now(),
+            last_error: None,
+            remote_ports: vec![],
+            bastion: None,
+            connections: ConnectionStats::default(),
+        });
+        StateTracker { tx }
+    }
+    pub fn subscribe(&self) -> watch::Receiver<TunnelStatus> {
+        self.tx.subscribe()
+    }
+    pub fn state(&self) -> TunnelState {
+        self.tx.borrow().state
+    }
+    /// moves to `next`, invalid transitions are logged and ignored
+    pub fn transition(&self, next: TunnelState) {
+        self.update(next, None);
+    }
+    /// moves to `next`, recording `error` as the reason
+    pub fn fail(&self, next: TunnelState, error: impl Display) {
+        self.update(next, Some(error.to_string()));
+    }
+    /// the remote ports bound on the server, empty when not forwarding
+    pub fn set_remote_ports(&self, ports: Vec<u16>) {
+        self.tx.send_if_modified(|status| {
+            let changed = status.remote_ports != ports;
+            status.remote_ports = ports;
+            changed
+        });
+    }
+    pub fn set_bastion(&self, bastion: Option<String>) {
+        self.tx.send_if_modified(|status| {
+            let changed = status.bastion != bastion;
+            status.bastion = bastion;
+            changed
+        });
+    }
+    pub fn set_connection_stats(&self, stats: ConnectionStats) {
+        self.tx.send_if_modified(|status| {
+            let changed = status.connections != stats;
+            status.connections = stats;
+            changed
+        });
+    }
+    fn update(&self, next: TunnelState, error: Option<String>) {
+        self.tx.send_if_modified(|status| {
+            if status.state == next && error.is_none() {
+                return false;
+            }
+            if status.state != next && !status.state.can_transition_to(next) {
+                warn!(
+                    "tunnel `{}` cannot go from {} to {}",
+                    status.name, status.state, next
+                );
+                return false;
+            }
+            if status.state != next {
+                info!("tunnel `{}`: {} -> {}", status.name, status.state, next);
+                status.state = next;
+                status.since = SystemTime::now();
+            }
+            if error.is_some() {
+                status.last_error = error;
+            }
+            true
+        });
+    }
+}
+
+/// how the first connection attempt of each tunnel went
+pub(crate) struct StartupReport {
+    pub started: SystemTime,
+    pub tunnels: Vec<TunnelStatus>,
+}
+impl StartupReport {
+    /// waits for every tunnel to be done with its first connection attempt, whatever
+    /// the outcome
+    pub async fn collect(
+        started: SystemTime,
+        statuses: Vec<watch::Receiver<TunnelStatus>>,
+    ) -> StartupReport {
+        let mut tunnels = join_all(statuses.into_iter().map(|mut status| async move {
+            // the sender is gone only once the tunnel is over, its status is final
+            let _ = status.wait_for(|s| !s.state.is_starting()).await;
+            status.borrow().clone()
+        }))
+        .await;
+        tunnels.sort_by(|a, b| a.name.cmp(&b.name));
+        StartupReport { started, tunnels }
+    }
+    pub fn forwarding(&self) -> usize {
+        self.tunnels
+            .iter()
+            .filter(|t| t.state == TunnelState::Forwarding)
+            .count()
+    }
+    pub fn log(&self) {
+        info!(
+            "startup: {}/{} tunnels forwarding",
+            self.forwarding(),
+            self.tunnels.len()
+        );
+        for tunnel in &self.tunnels {
+            let elapsed = tunnel
+                .since
+                .duration_since(self.started)
+                .unwrap_or_default();
+            match (&tunnel.state, &tunnel.last_error) {
+                (TunnelState::Forwarding, _) => info!(
+                    "tunnel `{}`: {} port {} through {} after {:?}",
+                    tunnel.name,
+                    tunnel.state,
+                    display_ports(&tunnel.remote_ports),
+                    tunnel.bastion.as_deref().unwrap_or("-"),
+                    elapsed
+                ),
+                (_, None) => info!(
+                    "tunnel `{}`: {} after {:?}",
+                    tunnel.name, tunnel.state, elapsed
+                ),
+                (_, Some(error)) => warn!(
+                    "tunnel `{}`: {} after {:?}: {error}",
+                    tunnel.name, tunnel.state, elapsed
+                ),
+            }
+        }
+    }
+}
+
+/// forwarded ports are either a single one or a contiguous range
+pub(crate) fn display_ports(ports: &[u16]) -> String {
+    match ports {
+        [] => String::from("-"),
+        [port] => port.to_string(),
+        [first, .., last] => format!("{first}-{last}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_the_lifecycle() {
+        let tracker = StateTracker::new("tunnel");
+        let status = tracker.subscribe();
+        for state in [
+            TunnelState::Connecting,
+            TunnelState::Authenticating,
+            TunnelState::Forwarding,
+        ] {
+            tracker.transition(state);
+            assert_eq!(status.borrow().state, state);
+        }
+        tracker.fail(TunnelState::BackingOff, "connection reset");
+        tracker.transition(TunnelState::Connecting);
+        tracker.transition(TunnelState::Authenticating);
+        // the next bastion is tried after an authentication failure
+        tracker.transition(TunnelState::Connecting);
+        tracker.transition(TunnelState::Connecting);
+        let status = status.borrow();
+        assert_eq!(status.state, TunnelState::Connecting);
+        assert_eq!(status.last_error.as_deref(), Some("connection reset"));
+    }
+
+    #[test]
+    fn invalid_transitions_are_ignored() {
+        let tracker = StateTracker::new("tunnel");
+        tracker.transition(TunnelState::Forwarding);
+        assert_eq!(tracker.state(), TunnelState::Idle);
+        tracker.transition(TunnelState::Standby);
+        assert!(!tracker.state().is_starting());
+        tracker.transition(TunnelState::Forwarding);
+        assert_eq!(tracker.state(), TunnelState::Standby);
+
+        tracker.transition(TunnelState::Stopping);
+        tracker.transition(TunnelState::Connecting);
+        assert_eq!(tracker.state(), TunnelState::Stopping);
+        tracker.transition(TunnelState::Stopped);
+        tracker.fail(TunnelState::Failed, "too late");
+        assert_eq!(tracker.state(), TunnelState::Stopped);
+        assert!(tracker.state().is_terminal());
+    }
+
+    #[tokio::test]
+    async fn startup_report_waits_for_the_first_attempts() {
+        let up = StateTracker::new("up");
+        let down = StateTracker::new("down");
+        let report = tokio::spawn(StartupReport::collect(
+            SystemTime::now(),
+            vec![up.subscribe(), down.subscribe()],
+        ));
+        up.transition(TunnelState::Connecting);
+        down.transition(TunnelState::Connecting);
+        up.transition(TunnelState::Authenticating);
+        up.transition(TunnelState::Forwarding);
+        tokio::task::yield_now().await;
+        assert!(!report.is_finished());
+
+        down.fail(TunnelState::BackingOff, "connection refused");
+        let report = report.await.unwrap();
+        assert_eq!(report.forwarding(), 1);
+        assert_eq!(report.tunnels[0].name, "down");
+        assert_eq!(report.tunnels[0].state, TunnelState::BackingOff);
+        assert_eq!(
+            report.tunnels[0].last_error.as_deref(),
+            Some("connection refused")
+        );
+    }
+}
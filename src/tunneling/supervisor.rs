@@ -0,0 +1,399 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::{Semaphore, mpsc::UnboundedReceiver, watch};
+use tracing::{error, info, warn};
+
+use crate::config::ReconnectConfig;
+
+use super::{
+    connections::ConnectionStats,
+    election::LeaderElection,
+    state::TunnelState,
+    tunnel::{SessionClosed, Tunnel, TunnelError},
+};
+
+/// exponential backoff with jitter between reconnection attempts
+pub(crate) struct Backoff {
+    config: ReconnectConfig,
+    attempt: u32,
+}
+impl Backoff {
+    pub fn new(config: ReconnectConfig) -> Backoff {
+        Backoff { config, attempt: 0 }
+    }
+    /// the delay to wait before the next attempt, `None` when attempts are exhausted
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self
+            .config
+            .max_attempts
+            .is_some_and(|max_attempts| self.attempt >= max_attempts)
+        {
+            return None;
+        }
+        let delay = self.delay(self.attempt, rand::random::<f64>());
+        self.attempt = self.attempt.saturating_add(1);
+        Some(delay)
+    }
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+    /// `initial_delay * multiplier^attempt`, capped to `max_delay`, minus up to
+    /// `jitter` of it: `random` is in [0, 1)
+    fn delay(&self, attempt: u32, random: f64) -> Duration {
+        let base = self.config.initial_delay_ms as f64
+            * self
+                .config
+                .multiplier
+                .powi(attempt.min(i32::MAX as u32) as i32);
+        let capped = base.min(self.config.max_delay_ms as f64);
+        let jitter = self.config.jitter.clamp(0.0, 1.0);
+        Duration::from_millis((capped * (1.0 - jitter * random)) as u64)
+    }
+}
+
+/// keeps a tunnel connected: it reconnects with backoff whenever the session
+/// drops or a connection attempt fails with a retryable error. It's the task
+/// owning the tunnel, so it drives the tunnel's lifecycle state
+pub(crate) struct TunnelSupervisor {
+    tunnel: Tunnel,
+    backoff: Backoff,
+    disconnections: UnboundedReceiver<SessionClosed>,
+    /// becomes `true` when the process is shutting down
+    shutdown: watch::Receiver<bool>,
+    drain_timeout: Duration,
+    /// shared by the tunnels of the same manager, limits how many of them can be
+    /// connecting at the same time
+    connect_limit: Option<Arc<Semaphore>>,
+    /// when replicas share the tunnel, it's forwarded only while holding the lease
+    election: Option<LeaderElection>,
+    /// failed and refused connections, published with the tunnel's status
+    stats: watch::Receiver<ConnectionStats>,
+}
+/// why `serve` returned
+enum Served {
+    Disconnected(String),
+    /// the lease went to another replica
+    Demoted(String),
+    Shutdown,
+}
+impl TunnelSupervisor {
+    pub fn new(
+        mut tunnel: Tunnel,
+        reconnect: ReconnectConfig,
+        shutdown: watch::Receiver<bool>,
+        drain_timeout: Duration,
+        connect_limit: Option<Arc<Semaphore>>,
+        election: Option<LeaderElection>,
+    ) -> TunnelSupervisor {
+        let disconnections = tunnel.disconnections();
+        let stats = tunnel.connections().stats();
+        if let Some(election) = &election {
+            tunnel.hold_lease(election.valid_until());
+        }
+        TunnelSupervisor {
+            tunnel,
+            backoff: Backoff::new(reconnect),
+            disconnections,
+            shutdown,
+            drain_timeout,
+            connect_limit,
+            election,
+            stats,
+        }
+    }
+    /// runs until the process shuts down, the tunnel fails with a fatal error or it
+    /// runs out of attempts
+    pub async fn run(mut self) -> Result<(), TunnelError> {
+        loop {
+            if !self.lead().await {
+                self.stop().await;
+                return Ok(());
+            }
+            // the lease is renewed while connecting, which can outlast it
+            let connected = tokio::select! {
+                connected = connect(&mut self.tunnel, self.connect_limit.as_deref()) => connected,
+                reason = keep(self.election.as_mut()) => {
+                    self.demote(&reason).await;
+                    continue;
+                }
+                _ = self.shutdown.wait_for(|shutdown| *shutdown) => {
+                    self.stop().await;
+                    return Ok(());
+                }
+            };
+            match connected {
+                Ok(()) => {
+                    self.backoff.reset();
+                    let reason = match self.serve().await {
+                        Served::Disconnected(reason) => reason,
+                        Served::Demoted(reason) => {
+                            self.demote(&reason).await;
+                            continue;
+                        }
+                        Served::Shutdown => {
+                            self.stop().await;
+                            return Ok(());
+                        }
+                    };
+                    warn!(
+                        "tunnel `{}` disconnected: {reason}, reconnecting",
+                        self.tunnel.name()
+                    );
+                    self.tunnel.state().fail(TunnelState::BackingOff, &reason);
+                }
+                Err(e @ TunnelError::LeaseLost(_)) => {
+                    self.demote(&e.to_string()).await;
+                    continue;
+                }
+                Err(e) if !e.is_retryable() => {
+                    error!(
+                        "tunnel `{}` failed, giving up: {}",
+                        self.tunnel.name(),
+                        e.to_string()
+                    );
+                    self.tunnel.state().fail(TunnelState::Failed, &e);
+                    self.release().await;
+                    return Err(e);
+                }
+                Err(e) => {
+                    warn!(
+                        "cannot connect tunnel `{}`: {}",
+                        self.tunnel.name(),
+                        e.to_string()
+                    );
+                    self.tunnel.state().fail(TunnelState::BackingOff, &e);
+                }
+            }
+            let Some(delay) = self.backoff.next_delay() else {
+                error!(
+                    "tunnel `{}` ran out of reconnection attempts",
+                    self.tunnel.name()
+                );
+                // the last error is kept, it's what made the attempts fail
+                self.tunnel.state().transition(TunnelState::Failed);
+                self.release().await;
+                return Err(TunnelError::ReconnectAttemptsExhausted(
+                    self.tunnel.name().to_owned(),
+                ));
+            };
+            info!(
+                "reconnecting tunnel `{}` in {:?}",
+                self.tunnel.name(),
+                delay
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                // while backing off the lease is kept as well, it's taken again first
+                // thing otherwise
+                reason = keep(self.election.as_mut()) => {
+                    warn!("tunnel `{}` lost its lease: {reason}", self.tunnel.name());
+                }
+                _ = self.shutdown.wait_for(|shutdown| *shutdown) => {
+                    self.stop().await;
+                    return Ok(());
+                }
+            }
+        }
+    }
+    /// waits until this replica holds the tunnel's lease, standing by meanwhile:
+    /// returns `false` if the process shuts down first
+    async fn lead(&mut self) -> bool {
+        let Some(election) = self.election.as_mut() else {
+            return true;
+        };
+        let mut standing_by = false;
+        loop {
+            match election.try_acquire().await {
+                Ok(true) => {
+                    if standing_by {
+                        info!(
+                            "{} takes tunnel `{}` over",
+                            election.holder(),
+                            self.tunnel.name()
+                        );
+                    }
+                    return true;
+                }
+                Ok(false) => {
+                    if !standing_by {
+                        info!(
+                            "tunnel `{}` stands by, another replica forwards it",
+                            self.tunnel.name()
+                        );
+                        self.tunnel.state().transition(TunnelState::Standby);
+                        standing_by = true;
+                    }
+                }
+                Err(e) => warn!(
+                    "cannot acquire the lease of tunnel `{}`: {}",
+                    self.tunnel.name(),
+                    e.to_string()
+                ),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(election.renew_interval()) => {}
+                _ = self.shutdown.wait_for(|shutdown| *shutdown) => return false,
+            }
+        }
+    }
+    /// releases what the tunnel holds, another replica forwards it now
+    async fn demote(&mut self, reason: &str) {
+        warn!("tunnel `{}` steps down: {reason}", self.tunnel.name());
+        self.tunnel.step_down("lease lost").await;
+        self.tunnel.state().fail(TunnelState::Standby, reason);
+    }
+    async fn release(&mut self) {
+        if let Some(election) = self.election.as_mut() {
+            election.release().await;
+        }
+    }
+    /// shuts the tunnel down, then lets a standby replica take over
+    async fn stop(&mut self) {
+        self.tunnel.shutdown(self.drain_timeout).await;
+        self.release().await;
+    }
+    /// waits for the current session to drop or for the process to shut down,
+    /// refreshing credentials and failing back to the preferred bastion in the meantime
+    async fn serve(&mut self) -> Served {
+        let mut refresh = self
+            .tunnel
+            .credentials_refresh_interval()
+            .map(tokio::time::interval);
+        if let Some(refresh) = refresh.as_mut() {
+            refresh.tick().await; // the first tick completes immediately
+        }
+        let mut failback = self.tunnel.failback_interval().map(tokio::time::interval);
+        if let Some(failback) = failback.as_mut() {
+            failback.tick().await;
+        }
+        let mut renew = self
+            .election
+            .as_ref()
+            .map(|election| tokio::time::interval(election.renew_interval()));
+        if let Some(renew) = renew.as_mut() {
+            renew.tick().await;
+        }
+        loop {
+            tokio::select! {
+                closed = self.disconnections.recv() => {
+                    match closed {
+                        Some(closed) if self.tunnel.is_current_session(closed.session_id) => {
+                            return Served::Disconnected(closed.reason);
+                        }
+                        // a session replaced after a credentials rotation
+                        Some(_) => continue,
+                        None => {
+                            return Served::Disconnected(String::from("session events channel closed"));
+                        }
+                    }
+                }
+                _ = self.shutdown.wait_for(|shutdown| *shutdown) => return Served::Shutdown,
+                Ok(()) = self.stats.changed() => {
+                    let stats = *self.stats.borrow_and_update();
+                    self.tunnel.state().set_connection_stats(stats);
+                }
+                _ = async { renew.as_mut().unwrap().tick().await }, if renew.is_some() => {
+                    if let Some(reason) = self.election.as_mut().unwrap().renew().await {
+                        return Served::Demoted(reason);
+                    }
+                }
+                _ = async { refresh.as_mut().unwrap().tick().await }, if refresh.is_some() => {
+                    if let Err(e) = self.tunnel.refresh_credentials().await {
+                        error!(
+                            "cannot refresh credentials of tunnel `{}`: {}",
+                            self.tunnel.name(),
+                            e.to_string()
+                        );
+                        if !self.tunnel.is_connected() {
+                            return Served::Disconnected(format!(
+                                "lost while switching credentials: {e}"
+                            ));
+                        }
+                    }
+                }
+                _ = async { failback.as_mut().unwrap().tick().await },
+                    if failback.is_some() && self.tunnel.is_failed_over() =>
+                {
+                    if let Err(e) = self.tunnel.fail_back().await {
+                        error!(
+                            "cannot fail back tunnel `{}`: {}",
+                            self.tunnel.name(),
+                            e.to_string()
+                        );
+                        if !self.tunnel.is_connected() {
+                            return Served::Disconnected(format!(
+                                "lost while failing back: {e}"
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// a single connection attempt, the tunnel bounds each bastion it tries by its
+/// connect timeout: waiting for the connect limit doesn't count
+async fn connect(
+    tunnel: &mut Tunnel,
+    connect_limit: Option<&Semaphore>,
+) -> Result<(), TunnelError> {
+    let _permit = match connect_limit {
+        Some(limit) => limit.acquire().await.ok(),
+        None => None,
+    };
+    tunnel.connect().await
+}
+/// keeps the lease until it's lost, returning why: never resolves without leader
+/// election
+async fn keep(election: Option<&mut LeaderElection>) -> String {
+    match election {
+        Some(election) => election.keep().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ReconnectConfig {
+        ReconnectConfig {
+            initial_delay_ms: 500,
+            max_delay_ms: 10_000,
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: None,
+        }
+    }
+
+    #[test]
+    fn delay_grows_exponentially_up_to_max_delay() {
+        let backoff = Backoff::new(config());
+        assert_eq!(backoff.delay(0, 0.0), Duration::from_millis(500));
+        assert_eq!(backoff.delay(1, 0.0), Duration::from_millis(1000));
+        assert_eq!(backoff.delay(4, 0.0), Duration::from_millis(8000));
+        assert_eq!(backoff.delay(5, 0.0), Duration::from_millis(10_000));
+        assert_eq!(backoff.delay(u32::MAX, 0.0), Duration::from_millis(10_000));
+    }
+
+    #[test]
+    fn jitter_shortens_the_delay() {
+        let backoff = Backoff::new(config());
+        assert_eq!(backoff.delay(1, 0.5), Duration::from_millis(750));
+        assert_eq!(backoff.delay(10, 0.75), Duration::from_millis(6250));
+    }
+
+    #[test]
+    fn attempts_are_limited() {
+        let mut backoff = Backoff::new(ReconnectConfig {
+            max_attempts: Some(2),
+            ..config()
+        });
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_none());
+        backoff.reset();
+        assert!(backoff.next_delay().is_some());
+    }
+}
@@ -1,45 +1,146 @@
 use russh::{
-    Channel,
-    client::{self, Handle, Session},
-    keys::{PrivateKey, PrivateKeyWithHashAlg, load_secret_key},
+    Channel, Disconnect,
+    client::{self, Handle},
+    keys::{PrivateKey, decode_secret_key, load_secret_key},
+};
+use std::{
+    fmt::Display,
+    future::Future,
+    net::AddrParseError,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
 };
-use std::{env::VarError, net::AddrParseError, sync::Arc};
 use thiserror::Error;
-use tokio::{sync::mpsc::Receiver, task::JoinHandle};
-use tracing::info;
+use tokio::{
+    sync::{
+        mpsc::{Receiver, Sender, UnboundedReceiver},
+        watch,
+    },
+    task::JoinHandle,
+};
+use tracing::{debug, info, warn};
 
 use crate::{
-    config::{PrivateKeyPassphrase, StorageConfig, TunnelConfig, TunnelType},
-    tunneling::handler::ClientHandler,
+    config::{PortRange, SecretError, StorageConfig, TunnelConfig, TunnelType},
+    storage::{self, Storage},
+    tunneling::handler::{ClientHandler, TunnelLink},
+};
+
+use super::{
+    auth::{Credentials, CredentialsSource},
+    connections::ConnectionRegistry,
+    pool::{BastionKey, Route, Routes, SessionPool, SharedSession},
+    state::{StateTracker, TunnelState, TunnelStatus, display_ports},
+    tunnel_runner::{ConnectionPolicy, TunnelRunner, dispatch},
 };
 
-use super::tunnel_runner::TunnelRunner;
+const DEFAULT_CREDENTIALS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_KEEPALIVE_MAX: usize = 3;
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_BACKEND_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_BACKEND_CONNECT_RETRIES: u32 = 2;
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+const DEFAULT_CONNECTION_QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+/// how many incoming connections can wait for the dispatcher while it waits for room,
+/// more are refused right away
+const DISPATCH_QUEUE_SIZE: usize = 32;
 
 pub(crate) struct Tunnel {
     /// tunnel name
     name: String,
-    /// public address of the machine you're using for tunneling
-    remote_ssh_address: String,
-    /// tunneling machine ssh port
-    remote_ssh_port: u16,
+    /// the machines you're using for tunneling, the preferred one first
+    bastions: Vec<Bastion>,
+    /// index of the bastion in use, or last tried
+    current: usize,
+    /// how often a more preferred bastion is tried while using a fallback one
+    failback_interval: Option<Duration>,
     /// the ssh user
     remote_ssh_user: String,
-    /// private key for connecting to the tunneling machine
-    private_key: PrivateKey,
+    /// credentials for connecting to the tunneling machine
+    credentials: CredentialsSource,
+    /// revision of the credentials used by the current session
+    credentials_revision: Option<u64>,
+    /// how often credentials are checked for changes
+    credentials_refresh_interval: Option<Duration>,
+    /// whether `from_command` helpers run again when checking credentials, they always
+    /// run when connecting
+    refresh_commands: bool,
     /// which interface the tunnel should be set on (127.0.0.1, 0.0.0.0, ...)
     remote_interface_address: String,
-    /// on which port should the tunnel bind remotely (on the tunneling machine)
-    remote_interface_port: u16,
+    /// on which ports should the tunnel bind remotely (on the tunneling machine), 0 lets
+    /// the server pick one
+    remote_interface_port: PortRange,
+    /// the port the server picked last time, it's requested again when reconnecting
+    allocated_port: Option<u16>,
+    /// whether `allocated_port` is kept in the storage, to survive restarts
+    persist_allocated_port: bool,
+    /// the ports currently bound on the server
+    remote_ports: Vec<u16>,
     /// tunneled service's address
     to_address: String,
-    /// tunneled service's port
-    to_port: u16,
-    /// clients connected to the tunnel
-    runners: Vec<TunnelRunner>,
-    /// ssh session
-    session_handle: Option<Handle<ClientHandler>>,
+    /// tunneled service's ports, either one for every remote port or a single one
+    to_port: PortRange,
+    /// ssh session, possibly shared with other tunnels to the same bastion
+    session: Option<Arc<SharedSession>>,
+    /// the session ports are being forwarded on, until that completes: when it fails,
+    /// times out or is cancelled, `abandon` releases what was bound meanwhile
+    forwarding: Option<Arc<SharedSession>>,
+    /// where sessions are shared among tunnels
+    pool: SessionPool,
+    /// shared with every session: incoming connections are handed over through it, so
+    /// that they keep being served after a new session replaces the old one
+    link: TunnelLink,
+    rx: Option<Receiver<(TunnelRunner, Channel<client::Msg>)>>,
+    /// serves the connections received through `rx`
+    dispatcher: Option<JoinHandle<()>>,
+    /// every forwarded connection holds a clone of `drain_tx` until it's over, so
+    /// `drain_rx` yields `None` once all of them are done
+    drain_tx: Option<Sender<()>>,
+    drain_rx: Receiver<()>,
+    /// identifies the current ssh session, sessions notify their disconnection
+    /// through the link
+    session_id: u64,
+    disconnect_rx: Option<UnboundedReceiver<SessionClosed>>,
     /// used to determine how to retrieve stored hosts
     storage_config: StorageConfig,
+    /// ssh client settings (keepalives, timeouts, ...)
+    ssh_config: Arc<client::Config>,
+    /// how long a connection attempt (handshake, authentication and forwarding) through
+    /// a single bastion can take
+    connect_timeout: Option<Duration>,
+    /// how many forwarded connections are handled at once
+    max_connections: usize,
+    /// how long a connection over `max_connections` waits before being refused
+    connection_queue_timeout: Duration,
+    /// how long opening the tcp connection and doing the ssh handshake can take
+    handshake_timeout: Option<Duration>,
+    auth_timeout: Option<Duration>,
+    /// lifecycle of the tunnel, observable through `status`
+    state: StateTracker,
+    /// until when this replica holds the tunnel's lease, with leader election
+    lease: Option<watch::Receiver<Option<Instant>>>,
+}
+/// a candidate ssh server, with the key its sessions are shared by
+struct Bastion {
+    address: String,
+    port: u16,
+    key: BastionKey,
+}
+impl Display for Bastion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.address, self.port)
+    }
+}
+/// sent by a session's handler when the ssh connection drops
+pub(crate) struct SessionClosed {
+    pub session_id: u64,
+    pub reason: String,
 }
 #[derive(Error, Debug)]
 pub enum TunnelError {
@@ -49,8 +150,8 @@ pub enum TunnelError {
     Io(std::io::Error, String),
     #[error("private key error: {1}")]
     PrivateKey(russh::keys::Error, String),
-    #[error("env variable for private key error: {0}")]
-    Env(String),
+    #[error("cannot resolve secret: {0}")]
+    Secret(String),
     #[error("ssh error: {0}")]
     Ssh(String),
     #[error("no rqlite config specified!")]
@@ -59,17 +160,89 @@ pub enum TunnelError {
     StorageLayer(String),
     #[error("someone is trying to do something nasty (cit.)")]
     NastyKey,
-    #[error(
-        "invalid passphrase configuration detected on tunnel `{0}`: \n
-        this usually happens when both from_env and value are not defined in [tunnels.private_key_passphrase]"
-    )]
-    InvalidPrivateKeyPassphraseConfiguration(String),
+    #[error("tunnel `{0}` uses a private key but has neither private_key nor private_key_path")]
+    MissingPrivateKey(String),
+    #[error("cannot load certificate `{0}`: {1}")]
+    Certificate(String, String),
+    #[error("authentication failed on tunnel `{0}`, methods tried: {}", .1.join(", "))]
+    AuthenticationFailed(String, Vec<String>),
+    #[error("{0} authentication interrupted: {1}")]
+    Authentication(&'static str, String),
+    #[error("tunnel `{0}` ran out of reconnection attempts")]
+    ReconnectAttemptsExhausted(String),
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("{0} timed out")]
+    Timeout(String),
+    #[error("ssh handshake with {0} timed out")]
+    HandshakeTimeout(String),
+    #[error("authentication on tunnel `{0}` timed out")]
+    AuthenticationTimeout(String),
+    #[error("connecting to {0} timed out")]
+    BackendTimeout(String),
+    #[error("connection closed after being idle for {0:?}")]
+    IdleTimeout(Duration),
+    #[error("connection closed after {0:?}, its maximum lifetime")]
+    LifetimeExceeded(Duration),
+    #[error("tunnel `{0}` lost its lease")]
+    LeaseLost(String),
+    #[error("every tunnel failed, none is up")]
+    AllTunnelsFailed,
+}
+impl TunnelError {
+    /// whether another bastion may do better: it couldn't be reached or it rejected
+    /// the credentials
+    fn fails_over(&self) -> bool {
+        matches!(
+            self,
+            TunnelError::Io(..)
+                | TunnelError::Ssh(_)
+                | TunnelError::Timeout(_)
+                | TunnelError::HandshakeTimeout(_)
+                | TunnelError::AuthenticationTimeout(_)
+                | TunnelError::AuthenticationFailed(..)
+                | TunnelError::Authentication(..)
+        )
+    }
+    /// whether trying again later can fix the error (network issues, timeouts, ...),
+    /// as opposed to errors that need a configuration change or a human
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            TunnelError::Io(..)
+            | TunnelError::Ssh(_)
+            | TunnelError::StorageLayer(_)
+            | TunnelError::Timeout(_)
+            | TunnelError::HandshakeTimeout(_)
+            | TunnelError::AuthenticationTimeout(_)
+            | TunnelError::Authentication(..)
+            | TunnelError::BackendTimeout(_)
+            | TunnelError::IdleTimeout(_)
+            | TunnelError::LifetimeExceeded(_)
+            | TunnelError::LeaseLost(_) => true,
+            TunnelError::InvalidAddress(_)
+            | TunnelError::PrivateKey(..)
+            | TunnelError::Secret(_)
+            | TunnelError::NoRqliteConfig
+            | TunnelError::NastyKey
+            | TunnelError::MissingPrivateKey(_)
+            | TunnelError::Certificate(..)
+            | TunnelError::AuthenticationFailed(..)
+            | TunnelError::ReconnectAttemptsExhausted(_)
+            | TunnelError::InvalidConfig(_)
+            | TunnelError::AllTunnelsFailed => false,
+        }
+    }
 }
 impl From<rqlite_rs::error::ClientBuilderError> for TunnelError {
     fn from(value: rqlite_rs::error::ClientBuilderError) -> Self {
         TunnelError::StorageLayer(value.to_string())
     }
 }
+impl From<SecretError> for TunnelError {
+    fn from(value: SecretError) -> Self {
+        Self::Secret(value.to_string())
+    }
+}
 impl From<AddrParseError> for TunnelError {
     fn from(value: AddrParseError) -> Self {
         Self::InvalidAddress(value.to_string())
@@ -94,110 +267,679 @@ impl From<russh::Error> for TunnelError {
 }
 
 impl Tunnel {
-    pub fn new(config: TunnelConfig, storage_config: StorageConfig) -> Result<Tunnel, TunnelError> {
-        let private_key = Tunnel::load_private_key(
-            &config.private_key_path,
-            &config.private_key_passphrase,
-            &config.name,
-        )?;
+    pub async fn new(
+        config: TunnelConfig,
+        storage_config: StorageConfig,
+        pool: SessionPool,
+    ) -> Result<Tunnel, TunnelError> {
+        if config.to_port.count() != 1
+            && config.to_port.count() != config.remote_interface_port.count()
+        {
+            return Err(TunnelError::InvalidConfig(format!(
+                "tunnel `{}` cannot forward ports {} to ports {}, the ranges must be as long",
+                config.name, config.remote_interface_port, config.to_port
+            )));
+        }
+        let credentials = CredentialsSource::new(&config).await?;
+        let bastions = config
+            .bastions()
+            .iter()
+            .map(|bastion| Bastion {
+                address: bastion.address.to_owned(),
+                port: bastion.port,
+                key: BastionKey::new(&config, bastion),
+            })
+            .collect();
+        let ssh_config = Arc::new(Tunnel::ssh_config(&config));
+        let (tx, rx) = tokio::sync::mpsc::channel(DISPATCH_QUEUE_SIZE);
+        let (disconnect_tx, disconnect_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (drain_tx, drain_rx) = tokio::sync::mpsc::channel(1);
         Ok(Tunnel {
+            state: StateTracker::new(&config.name),
             name: config.name,
-            private_key,
+            credentials,
+            credentials_revision: None,
+            credentials_refresh_interval: match config.credentials_refresh_interval {
+                Some(0) => None,
+                Some(seconds) => Some(Duration::from_secs(seconds)),
+                None => Some(DEFAULT_CREDENTIALS_REFRESH_INTERVAL),
+            },
+            refresh_commands: config.credentials_refresh_commands.unwrap_or(false),
             remote_interface_address: config.remote_interface_address,
             remote_interface_port: config.remote_interface_port,
-            remote_ssh_address: config.remote_ssh_address,
-            remote_ssh_port: config.remote_ssh_port,
+            allocated_port: None,
+            persist_allocated_port: config.persist_allocated_port.unwrap_or(false),
+            remote_ports: vec![],
+            bastions,
+            current: 0,
+            failback_interval: config
+                .failback_interval
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs),
             remote_ssh_user: config.remote_ssh_user,
             to_address: config.to_address,
             to_port: config.to_port,
-            runners: Vec::new(),
-            session_handle: None,
+            session: None,
+            forwarding: None,
+            lease: None,
+            pool,
+            link: TunnelLink {
+                tx,
+                disconnect_tx,
+                accepting: Arc::new(AtomicBool::new(true)),
+                connections: ConnectionRegistry::default(),
+                policy: ConnectionPolicy {
+                    backend_connect: timeout(
+                        config.backend_connect_timeout,
+                        DEFAULT_BACKEND_CONNECT_TIMEOUT,
+                    ),
+                    backend_retries: config
+                        .backend_connect_retries
+                        .unwrap_or(DEFAULT_BACKEND_CONNECT_RETRIES),
+                    bad_gateway: config.tun_type == TunnelType::Http,
+                    idle: config
+                        .idle_timeout
+                        .filter(|seconds| *seconds > 0)
+                        .map(Duration::from_secs),
+                    lifetime: config
+                        .max_connection_lifetime
+                        .filter(|seconds| *seconds > 0)
+                        .map(Duration::from_secs),
+                },
+                max_per_originator: config.max_connections_per_originator,
+            },
+            rx: Some(rx),
+            dispatcher: None,
+            drain_tx: Some(drain_tx),
+            drain_rx,
+            session_id: 0,
+            disconnect_rx: Some(disconnect_rx),
             storage_config,
+            ssh_config,
+            connect_timeout: timeout(config.connect_timeout, DEFAULT_CONNECT_TIMEOUT),
+            handshake_timeout: timeout(config.handshake_timeout, DEFAULT_HANDSHAKE_TIMEOUT),
+            auth_timeout: timeout(config.auth_timeout, DEFAULT_AUTH_TIMEOUT),
+            max_connections: config
+                .max_connections
+                .unwrap_or(DEFAULT_MAX_CONNECTIONS)
+                .max(1),
+            connection_queue_timeout: config
+                .connection_queue_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_CONNECTION_QUEUE_TIMEOUT),
         })
     }
-    pub async fn connect(&mut self) -> Result<JoinHandle<()>, TunnelError> {
-        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
-        let config = client::Config::default();
-        let config = Arc::new(config);
-        let mut session = client::connect(
-            config,
-            (self.remote_ssh_address.to_owned(), self.remote_ssh_port),
-            ClientHandler::new(
-                &self.to_address,
-                self.to_port,
-                &self.remote_ssh_address,
-                self.remote_ssh_port,
-                self.storage_config.clone(),
-                tx,
-            )
-            .await?,
+    /// missed keepalives and inactivity make russh close the session, which is then
+    /// reported as a disconnection and reconnected by the supervisor
+    fn ssh_config(config: &TunnelConfig) -> client::Config {
+        let keepalive_interval = match config.keepalive_interval {
+            Some(0) => None,
+            Some(seconds) => Some(Duration::from_secs(seconds)),
+            None => Some(DEFAULT_KEEPALIVE_INTERVAL),
+        };
+        client::Config {
+            keepalive_interval,
+            keepalive_max: config.keepalive_max.unwrap_or(DEFAULT_KEEPALIVE_MAX),
+            inactivity_timeout: config
+                .inactivity_timeout
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs),
+            ..Default::default()
+        }
+    }
+    /// joins (or opens) the ssh session to a bastion and starts forwarding the remote
+    /// port, it can be called again after the session drops. Bastions are tried in
+    /// order of preference, until one accepts the tunnel
+    pub async fn connect(&mut self) -> Result<(), TunnelError> {
+        self.abandon().await;
+        // the old session is gone, other tunnels may still hold it until they notice
+        self.session = None;
+        self.remote_ports.clear();
+        self.state.set_remote_ports(vec![]);
+        self.state.set_bastion(None);
+        let credentials = self.credentials.load(true).await?;
+        let mut failure: Option<TunnelError> = None;
+        let mut session = None;
+        for index in 0..self.bastions.len() {
+            self.state.transition(TunnelState::Connecting);
+            match self.attempt(index, &credentials).await {
+                Ok(joined) => {
+                    session = Some(joined);
+                    break;
+                }
+                Err(e) if e.fails_over() => {
+                    warn!(
+                        "tunnel `{}` cannot go through {}: {}",
+                        self.name,
+                        self.bastions[index],
+                        e.to_string()
+                    );
+                    // a retryable error is kept, so that the supervisor tries again
+                    if failure.as_ref().is_none_or(|f| !f.is_retryable()) {
+                        failure = Some(e);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let Some(session) = session else {
+            self.abandon().await;
+            return Err(failure.expect("a tunnel has at least a bastion"));
+        };
+        self.state.transition(TunnelState::Forwarding);
+        self.session_id = session.id;
+        self.session = Some(session);
+        self.credentials_revision = Some(credentials.revision());
+
+        if let (Some(rx), Some(drain_tx)) = (self.rx.take(), self.drain_tx.clone()) {
+            self.dispatcher = Some(tokio::spawn(dispatch(
+                self.name.to_owned(),
+                rx,
+                self.link.connections.clone(),
+                drain_tx,
+                self.max_connections,
+                self.connection_queue_timeout,
+            )));
+        }
+        info!(
+            "tunnel to {}:{} through {}:{} running",
+            self.to_address,
+            self.to_port,
+            self.bastions[self.current].address,
+            display_ports(&self.remote_ports)
+        );
+
+        Ok(())
+    }
+    /// joins the session to a bastion and forwards the remote ports through it
+    async fn attempt(
+        &mut self,
+        index: usize,
+        credentials: &Credentials,
+    ) -> Result<Arc<SharedSession>, TunnelError> {
+        if index != self.current {
+            // ports are allocated by each bastion independently
+            self.allocated_port = None;
+            self.current = index;
+        }
+        // a timed out attempt may have left ports bound
+        self.abandon().await;
+        self.remote_ports.clear();
+        let what = format!(
+            "connecting tunnel `{}` to {}",
+            self.name, self.bastions[index]
+        );
+        let timeout = self.connect_timeout;
+        within(timeout, TunnelError::Timeout(what), async {
+            let session = self.join_session(index, credentials).await?;
+            self.forward(&session).await?;
+            self.state
+                .set_bastion(Some(self.bastions[index].to_string()));
+            Ok(session)
+        })
+        .await
+    }
+    /// notifications about dropped sessions, they can be taken only once
+    pub fn disconnections(&mut self) -> UnboundedReceiver<SessionClosed> {
+        self.disconnect_rx
+            .take()
+            .expect("tunnel disconnections can be taken only once")
+    }
+    /// the lifecycle of the tunnel, updated by whoever drives it
+    pub fn state(&self) -> &StateTracker {
+        &self.state
+    }
+    pub fn status(&self) -> watch::Receiver<TunnelStatus> {
+        self.state.subscribe()
+    }
+    /// the connections currently forwarded, they can be listed and cancelled
+    pub fn connections(&self) -> ConnectionRegistry {
+        self.link.connections.clone()
+    }
+    pub fn is_connected(&self) -> bool {
+        self.session.is_some()
+    }
+    pub fn is_current_session(&self, session_id: u64) -> bool {
+        self.session.is_some() && self.session_id == session_id
+    }
+    pub fn failback_interval(&self) -> Option<Duration> {
+        self.failback_interval
+    }
+    /// whether the tunnel goes through a fallback bastion
+    pub fn is_failed_over(&self) -> bool {
+        self.session.is_some() && self.current > 0
+    }
+    pub fn credentials_refresh_interval(&self) -> Option<Duration> {
+        self.credentials_refresh_interval
+    }
+    /// reloads the credentials: when they change (e.g. a rotated key file), the
+    /// tunnel moves to a new session authenticated with the new ones
+    pub async fn refresh_credentials(&mut self) -> Result<(), TunnelError> {
+        let credentials = self.credentials.load(self.refresh_commands).await?;
+        if self.credentials_revision == Some(credentials.revision()) {
+            return Ok(());
+        }
+        info!(
+            "credentials of tunnel `{}` changed, re-authenticating",
+            self.name
+        );
+        // authenticate first, so that the current session keeps running if the new
+        // credentials are rejected
+        let what = format!("re-authenticating tunnel `{}`", self.name);
+        let session = within(
+            self.connect_timeout,
+            TunnelError::Timeout(what),
+            self.join_session(self.current, &credentials),
         )
         .await?;
-        session
-            .authenticate_publickey(
-                self.remote_ssh_user.clone(),
-                PrivateKeyWithHashAlg::new(
-                    Arc::new(self.private_key.to_owned()),
-                    session.best_supported_rsa_hash().await.unwrap().flatten(),
-                ),
-            )
+        self.switch(session, self.current, "credentials rotated")
             .await?;
-
-        session
-            .tcpip_forward(
-                self.remote_interface_address.to_owned(),
-                self.remote_interface_port as u32, // u32 for some reason??
+        self.credentials_revision = Some(credentials.revision());
+        info!("tunnel `{}` is using the new credentials", self.name);
+        Ok(())
+    }
+    /// moves the tunnel back to the most preferred bastion that can be reached again,
+    /// the current session keeps running if none can
+    pub async fn fail_back(&mut self) -> Result<(), TunnelError> {
+        if !self.is_failed_over() {
+            return Ok(());
+        }
+        let credentials = self.credentials.load(self.refresh_commands).await?;
+        for index in 0..self.current {
+            let what = format!(
+                "connecting tunnel `{}` to {}",
+                self.name, self.bastions[index]
+            );
+            let joined = within(
+                self.connect_timeout,
+                TunnelError::Timeout(what),
+                self.join_session(index, &credentials),
             )
-            .await?; // this asks the server to open the specified port on the remote interface
-        session.channel_open_session().await.unwrap();
-        self.session_handle = Some(session);
-
-        let handler = tokio::spawn(async move {
-            while let Some((mut runner, chan)) = rx.recv().await {
-                runner.run(chan).await.expect("runner error");
+            .await;
+            match joined {
+                Ok(session) => {
+                    info!(
+                        "tunnel `{}` fails back to {}",
+                        self.name, self.bastions[index]
+                    );
+                    self.allocated_port = None;
+                    self.switch(session, index, "failing back").await?;
+                    self.state
+                        .set_bastion(Some(self.bastions[index].to_string()));
+                    return Ok(());
+                }
+                Err(e) => debug!(
+                    "tunnel `{}` cannot fail back to {} yet: {}",
+                    self.name,
+                    self.bastions[index],
+                    e.to_string()
+                ),
+            }
+        }
+        Ok(())
+    }
+    /// moves the remote ports from the current session to `session`, through the
+    /// `bastion`-th bastion: if forwarding fails the tunnel is left disconnected, and
+    /// the supervisor reconnects it
+    async fn switch(
+        &mut self,
+        session: Arc<SharedSession>,
+        bastion: usize,
+        reason: &str,
+    ) -> Result<(), TunnelError> {
+        if let Some(old_session) = self.session.take() {
+            // the remote port can be bound by a single session at a time
+            self.unforward(&old_session).await;
+            Tunnel::leave(old_session, reason).await;
+        }
+        self.current = bastion;
+        if let Err(e) = self.forward(&session).await {
+            self.abandon().await;
+            return Err(e);
+        }
+        self.session_id = session.id;
+        self.session = Some(session);
+        Ok(())
+    }
+    /// stops accepting connections and releases the remote port, then waits up to
+    /// `drain_timeout` for the forwarded connections to finish before disconnecting
+    pub async fn shutdown(&mut self, drain_timeout: Duration) {
+        self.state.transition(TunnelState::Stopping);
+        self.link.accepting.store(false, Ordering::SeqCst);
+        self.abandon().await;
+        if let Some(session) = self.session.clone() {
+            self.unforward(&session).await;
+        }
+        // nothing new reaches the dispatcher anymore, its guard can go
+        if let Some(dispatcher) = self.dispatcher.take() {
+            dispatcher.abort();
+        }
+        self.drain_tx.take();
+        match tokio::time::timeout(drain_timeout, self.drain_rx.recv()).await {
+            Ok(_) => info!("tunnel `{}` drained", self.name),
+            Err(_) => warn!(
+                "tunnel `{}` not drained after {:?}, closing {} remaining connections",
+                self.name,
+                drain_timeout,
+                self.link.connections.cancel_all()
+            ),
+        }
+        if let Some(session) = self.session.take() {
+            Tunnel::leave(session, "shutting down").await;
+        }
+        self.state.transition(TunnelState::Stopped);
+    }
+    /// forwards only while the lease is held, as published by the election
+    pub fn hold_lease(&mut self, lease: watch::Receiver<Option<Instant>>) {
+        self.lease = Some(lease);
+    }
+    /// releases the remote ports and leaves the session without stopping, the tunnel
+    /// can connect again later
+    pub async fn step_down(&mut self, reason: &str) {
+        self.abandon().await;
+        if let Some(session) = self.session.take() {
+            self.unforward(&session).await;
+            Tunnel::leave(session, reason).await;
+        }
+        self.state.set_bastion(None);
+    }
+    /// returns the authenticated session to the `index`-th bastion, opening it unless
+    /// another tunnel using the same credentials already did
+    async fn join_session(
+        &self,
+        index: usize,
+        credentials: &Credentials,
+    ) -> Result<Arc<SharedSession>, TunnelError> {
+        let bastion = &self.bastions[index];
+        let slot = self
+            .pool
+            .slot(bastion.key.with_credentials(credentials.revision()));
+        // held while connecting, the other tunnels to this bastion wait for the session
+        let mut slot = slot.lock().await;
+        // credentials are refreshed while forwarding, that's not a new connection
+        let connecting = self.state.state() == TunnelState::Connecting;
+        if let Some(session) = slot.upgrade().filter(|s| !s.handle.is_closed()) {
+            info!(
+                "tunnel `{}` shares session {} to {}",
+                self.name, session.id, bastion
+            );
+            if connecting {
+                self.state.transition(TunnelState::Authenticating);
             }
+            return Ok(session);
+        }
+        let routes = Routes::default();
+        let (mut handle, session_id) = self.handshake(bastion, routes.clone()).await?;
+        if connecting {
+            self.state.transition(TunnelState::Authenticating);
+        }
+        within(
+            self.auth_timeout,
+            TunnelError::AuthenticationTimeout(self.name.to_owned()),
+            credentials.authenticate(&mut handle, &self.remote_ssh_user, &self.name),
+        )
+        .await?;
+        let session = Arc::new(SharedSession {
+            id: session_id,
+            handle,
+            routes,
         });
+        *slot = Arc::downgrade(&session);
+        Ok(session)
+    }
+    /// connects to the ssh server, the session still needs to be authenticated
+    async fn handshake(
+        &self,
+        bastion: &Bastion,
+        routes: Routes,
+    ) -> Result<(Handle<ClientHandler>, u64), TunnelError> {
+        // ids are never reused, not even for failed attempts
+        let session_id = self.pool.next_session_id();
+        let handler = ClientHandler::new(
+            &bastion.address,
+            bastion.port,
+            self.storage_config.clone(),
+            routes,
+            session_id,
+        )
+        .await?;
+        let session = within(
+            self.handshake_timeout,
+            TunnelError::HandshakeTimeout(bastion.to_string()),
+            async {
+                // known hosts are checked against the entry of this very bastion
+                Ok(client::connect(
+                    self.ssh_config.clone(),
+                    (bastion.address.to_owned(), bastion.port),
+                    handler,
+                )
+                .await?)
+            },
+        )
+        .await?;
+        Ok((session, session_id))
+    }
+    /// asks the server to open the remote ports, their channels are routed to this
+    /// tunnel. Ports are recorded before being asked for, so that `abandon` can
+    /// release them if this doesn't complete
+    async fn forward(&mut self, session: &Arc<SharedSession>) -> Result<(), TunnelError> {
+        // connecting may have taken longer than the lease lasts
+        if self
+            .lease
+            .as_ref()
+            .is_some_and(|lease| lease.borrow().is_none_or(|until| until <= Instant::now()))
+        {
+            return Err(TunnelError::LeaseLost(self.name.to_owned()));
+        }
+        self.forwarding = Some(session.clone());
+        let address = self.remote_interface_address.to_owned();
+        if self.remote_interface_port.start == 0 {
+            self.allocate_port(session, &address).await?;
+        } else {
+            for (index, port) in self.remote_interface_port.ports().enumerate() {
+                // routed before asking, the first channels can arrive before the reply
+                session
+                    .routes
+                    .add(self.route(&address, port as u32, self.target_port(index)));
+                self.remote_ports.push(port);
+                // u32 for some reason??
+                if let Err(e) = session
+                    .handle
+                    .tcpip_forward(address.to_owned(), port as u32)
+                    .await
+                {
+                    self.remote_ports.pop();
+                    session.routes.remove(&address, port as u32);
+                    // all or nothing: the ports bound so far are released
+                    self.abandon().await;
+                    return Err(e.into());
+                }
+            }
+        }
+        // no session channel is opened: forwarding doesn't need one, and every tunnel
+        // sharing the session would count against the server's `MaxSessions`
+        self.state.set_remote_ports(self.remote_ports.clone());
+        self.forwarding = None;
+        Ok(())
+    }
+    /// undoes forwarding that didn't complete: ports may be bound and routed on a
+    /// session other tunnels can share
+    async fn abandon(&mut self) {
+        if let Some(session) = self.forwarding.take() {
+            self.unforward(&session).await;
+            Tunnel::leave(session, "forwarding abandoned").await;
+        }
+    }
+    /// the port of the tunneled service for the `index`-th remote port
+    fn target_port(&self, index: usize) -> u16 {
+        if self.to_port.count() == 1 {
+            self.to_port.start
+        } else {
+            self.to_port.start + index as u16
+        }
+    }
+    /// asks again for the port allocated last time, or for any free port when that one
+    /// is not available anymore. The port is added to `remote_ports`
+    async fn allocate_port(
+        &mut self,
+        session: &SharedSession,
+        address: &str,
+    ) -> Result<(), TunnelError> {
+        if self.allocated_port.is_none() && self.persist_allocated_port {
+            self.allocated_port = self.stored_port().await;
+        }
+        if let Some(port) = self.allocated_port {
+            session
+                .routes
+                .add(self.route(address, port as u32, self.to_port.start));
+            self.remote_ports = vec![port];
+            match session
+                .handle
+                .tcpip_forward(address.to_owned(), port as u32)
+                .await
+            {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    self.remote_ports.clear();
+                    session.routes.remove(address, port as u32);
+                    warn!(
+                        "tunnel `{}` cannot get port {port} back: {}, asking for a new one",
+                        self.name,
+                        e.to_string()
+                    );
+                }
+            }
+        }
+        let port = session.handle.tcpip_forward(address.to_owned(), 0).await?;
+        let port = u16::try_from(port).map_err(|_| {
+            TunnelError::Ssh(format!("the server allocated an invalid port {port}"))
+        })?;
+        // channels can't arrive before the server tells which port it picked
+        session
+            .routes
+            .add(self.route(address, port as u32, self.to_port.start));
+        self.remote_ports = vec![port];
         info!(
-            "tunnel to {}:{} through {} running",
-            self.to_address, self.to_port, self.remote_ssh_address
+            "tunnel `{}` got port {port} on {}:{address}",
+            self.name, self.bastions[self.current].address
         );
-
-        Ok(handler)
+        self.allocated_port = Some(port);
+        if self.persist_allocated_port {
+            self.store_port(port).await;
+        }
+        Ok(())
+    }
+    async fn stored_port(&self) -> Option<u16> {
+        let stored = match self.storage().await {
+            Ok(storage) => storage
+                .get_allocated_port(&self.name, &self.bastions[self.current].address)
+                .await
+                .map_err(TunnelError::from),
+            Err(e) => Err(e),
+        };
+        stored.unwrap_or_else(|e| {
+            warn!(
+                "cannot read the port stored for tunnel `{}`: {}",
+                self.name,
+                e.to_string()
+            );
+            None
+        })
+    }
+    async fn store_port(&self, port: u16) {
+        let stored = match self.storage().await {
+            Ok(storage) => storage
+                .store_allocated_port(&self.name, &self.bastions[self.current].address, port)
+                .await
+                .map_err(TunnelError::from),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = stored {
+            warn!(
+                "cannot store the port of tunnel `{}`: {}",
+                self.name,
+                e.to_string()
+            );
+        }
+    }
+    async fn storage(&self) -> Result<Box<dyn Storage>, TunnelError> {
+        let storage = storage::get_storage(self.storage_config.clone()).await?;
+        storage.ensure().await?;
+        Ok(storage)
+    }
+    fn route(&self, address: &str, port: u32, to_port: u16) -> Route {
+        Route {
+            address: address.to_owned(),
+            port,
+            to_addr: self.to_address.to_owned(),
+            to_port,
+            link: self.link.clone(),
+        }
+    }
+    /// releases the remote ports, the session keeps serving the other tunnels
+    async fn unforward(&mut self, session: &SharedSession) {
+        self.state.set_remote_ports(vec![]);
+        let address = self.remote_interface_address.to_owned();
+        for port in std::mem::take(&mut self.remote_ports) {
+            if let Err(e) = session
+                .handle
+                .cancel_tcpip_forward(address.to_owned(), port as u32)
+                .await
+            {
+                warn!(
+                    "cannot cancel forwarding of port {port} on tunnel `{}`: {}",
+                    self.name,
+                    e.to_string()
+                );
+            }
+            session.routes.remove(&address, port as u32);
+        }
+    }
+    /// gives up the session, which is disconnected if no other tunnel uses it
+    async fn leave(session: Arc<SharedSession>, reason: &str) {
+        if let Some(session) = Arc::into_inner(session) {
+            let _ = session
+                .handle
+                .disconnect(Disconnect::ByApplication, reason, "en")
+                .await;
+        }
     }
     pub fn name(&self) -> &str {
         &self.name
     }
-    fn load_private_key(
+    pub(super) fn load_private_key(
         key_path: &str,
-        passphrase: &Option<PrivateKeyPassphrase>,
-        tunnel_name: &str,
+        passphrase: Option<&str>,
     ) -> Result<PrivateKey, TunnelError> {
-        if let Some(passphrase) = passphrase {
-            match passphrase {
-                PrivateKeyPassphrase {
-                    value: Some(plaintext_key),
-                    from_env: None,
-                } => Ok(load_secret_key(key_path, Some(plaintext_key))?),
-                PrivateKeyPassphrase {
-                    from_env: Some(env_var),
-                    value: None,
-                } => {
-                    let env_value = std::env::var(env_var).map_err(|e| match e {
-                        VarError::NotPresent => {
-                            TunnelError::Env("{env_var} not found in the environment!".to_string())
-                        }
-                        VarError::NotUnicode(_) => {
-                            TunnelError::Env("{env_var} is not unicode!".to_string())
-                        }
-                    })?;
-                    Ok(load_secret_key(key_path, Some(&env_value))?)
-                }
-                _ => Err(TunnelError::InvalidPrivateKeyPassphraseConfiguration(
-                    tunnel_name.to_owned(),
-                )),
-            }
-        } else {
-            Ok(load_secret_key(key_path, None)?)
-        }
+        Ok(load_secret_key(key_path, passphrase)?)
+    }
+    /// parses an openssh private key kept in memory, it never touches the disk
+    pub(super) fn decode_private_key(
+        key: &str,
+        passphrase: Option<&str>,
+    ) -> Result<PrivateKey, TunnelError> {
+        Ok(decode_secret_key(key, passphrase)?)
+    }
+}
+
+/// bounds `future` to `timeout`, failing with `expired` when it runs out
+async fn within<T>(
+    timeout: Option<Duration>,
+    expired: TunnelError,
+    future: impl Future<Output = Result<T, TunnelError>>,
+) -> Result<T, TunnelError> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| expired)?,
+        None => future.await,
+    }
+}
+/// a timeout in seconds from the configuration, 0 disables it
+fn timeout(seconds: Option<u64>, default: Duration) -> Option<Duration> {
+    match seconds {
+        Some(0) => None,
+        Some(seconds) => Some(Duration::from_secs(seconds)),
+        None => Some(default),
     }
 }
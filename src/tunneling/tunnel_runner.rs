@@ -1,15 +1,89 @@
-use russh::{Channel, client};
+use std::{sync::Arc, time::Duration};
+
+use russh::{Channel, ChannelStream, client};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, Join},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
+    sync::{
+        Semaphore,
+        mpsc::{Receiver, Sender, error::TrySendError},
+    },
     task::JoinHandle,
+    time::Instant,
+};
+use tracing::{debug, error, info, warn};
+
+use super::{
+    connections::{Connection, ConnectionRegistry, Counters},
+    tunnel::TunnelError,
 };
 
-use super::tunnel::{Tunnel, TunnelError};
+/// the delay before the first retry to connect to the tunneled service, it doubles at
+/// every retry
+const BACKEND_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// one per direction, allocated once: ssh packets carry up to 32 KiB
+const BUFFER_SIZE: usize = 64 * 1024;
+/// sent to http clients when the tunneled service can't be reached
+const BAD_GATEWAY: &[u8] = b"HTTP/1.1 502 Bad Gateway\r\ncontent-type: text/plain\r\ncontent-length: 11\r\nconnection: close\r\n\r\nBad Gateway";
+/// sent to http clients refused because the tunnel handles too many connections
+const SERVICE_UNAVAILABLE: &[u8] = b"HTTP/1.1 503 Service Unavailable\r\ncontent-type: text/plain\r\ncontent-length: 19\r\nconnection: close\r\n\r\nService Unavailable";
+
+/// the client side of a forwarded connection, an ssh channel
+pub(crate) trait ClientChannel: Send + 'static {
+    type Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+    /// closes the channel, after sending `response` when there is one
+    fn close_with(self, response: Option<&'static [u8]>) -> impl Future<Output = ()> + Send;
+    fn into_stream(self) -> Self::Stream;
+}
+impl ClientChannel for Channel<client::Msg> {
+    type Stream = ChannelStream<client::Msg>;
+    async fn close_with(self, response: Option<&'static [u8]>) {
+        if let Some(response) = response {
+            let _ = self.data(response).await;
+        }
+        let _ = self.eof().await;
+        let _ = self.close().await;
+    }
+    fn into_stream(self) -> Self::Stream {
+        Channel::into_stream(self)
+    }
+}
+
+/// how forwarded connections are handled, timeouts are disabled when `None`
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ConnectionPolicy {
+    /// for connecting to the tunneled service, retries included
+    pub backend_connect: Option<Duration>,
+    /// how many times connecting to the tunneled service is retried
+    pub backend_retries: u32,
+    /// whether clients get a 502 response when the tunneled service can't be reached,
+    /// and a 503 when refused, otherwise the channel is just closed
+    pub bad_gateway: bool,
+    /// without traffic in either direction
+    pub idle: Option<Duration>,
+    /// since the connection was opened
+    pub lifetime: Option<Duration>,
+}
+impl ConnectionPolicy {
+    /// closes the channel of a connection over the tunnel's limits
+    pub async fn refuse(&self, channel: impl ClientChannel) {
+        self.reject(channel, SERVICE_UNAVAILABLE).await;
+    }
+    /// closes the channel of a connection that can't be served, so that the client
+    /// doesn't hang. Http clients get `response` first
+    async fn reject(&self, channel: impl ClientChannel, response: &'static [u8]) {
+        channel
+            .close_with(self.bad_gateway.then_some(response))
+            .await;
+    }
+}
 
 pub(crate) struct TunnelRunner {
     to_addr: String,
     to_port: u16,
+    /// registers the connection with its tunnel until it's over
+    connection: Connection,
+    policy: ConnectionPolicy,
 }
 type RunResult = tokio::task::JoinHandle<
     std::result::Result<std::result::Result<(), TunnelError>, tokio::task::JoinError>,
@@ -21,58 +95,616 @@ impl TunnelRunner {
     pub fn port(&self) -> u16 {
         self.to_port
     }
-    pub fn new(to_addr: &str, to_port: u16) -> Result<TunnelRunner, TunnelError> {
+    pub fn new(
+        to_addr: &str,
+        to_port: u16,
+        connection: Connection,
+        policy: ConnectionPolicy,
+    ) -> Result<TunnelRunner, TunnelError> {
         Ok(TunnelRunner {
             to_addr: to_addr.to_string(),
             to_port,
+            connection,
+            policy,
         })
     }
-    pub async fn run(&mut self, channel: Channel<client::Msg>) -> Result<RunResult, TunnelError> {
-        let mut writer = channel.make_writer();
-        let mut stream = channel.into_stream();
-        let conn = TcpStream::connect((self.to_addr.to_string(), self.to_port)).await?;
+    /// closes the channel of a connection over the tunnel's limits
+    pub async fn refuse(self, channel: impl ClientChannel) {
+        self.policy.refuse(channel).await;
+    }
+    /// forwards the channel to the tunneled service: when it can't be reached the
+    /// channel is closed, after a 502 response for http tunnels
+    pub async fn run(self, channel: impl ClientChannel) -> Result<RunResult, TunnelError> {
+        let conn = match self.connect_backend().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                self.connection.failed();
+                self.policy.reject(channel, BAD_GATEWAY).await;
+                return Err(e);
+            }
+        };
+        // shutting the channel's write half down sends EOF to the client
+        let (mut stream, mut writer) = tokio::io::split(channel.into_stream());
+        let policy = self.policy;
         let (mut rx, mut tx) = conn.into_split();
+        let connection = self.connection;
+        connection.opened();
+        let received = connection.counters();
+        let sent = connection.counters();
+        let activity = connection.counters();
 
-        let reading_handle: JoinHandle<Result<(), TunnelError>> = tokio::spawn(async move {
-            loop {
-                let mut buf = vec![0u8; 4096];
-                if let Ok(n) = stream.read(&mut buf).await {
-                    if n == 0 {
-                        break;
+        // client -> service
+        let mut reading_handle: JoinHandle<Result<(), TunnelError>> = tokio::spawn(async move {
+            pipe(&mut stream, &mut tx, |n| received.received(n))
+                .await
+                .map_err(|(e, side)| io_error(e, "client -> service", side))
+        });
+        // service -> client
+        let mut writing_handle: JoinHandle<Result<(), TunnelError>> = tokio::spawn(async move {
+            pipe(&mut rx, &mut writer, |n| sent.sent(n))
+                .await
+                .map_err(|(e, side)| io_error(e, "service -> client", side))
+        });
+        let select_future = tokio::spawn(async move {
+            let lifetime = tokio::time::sleep(policy.lifetime.unwrap_or_default());
+            tokio::pin!(lifetime);
+            let (mut reading_done, mut writing_done) = (false, false);
+            let result = loop {
+                tokio::select! {
+                    read_result = &mut reading_handle, if !reading_done => {
+                        reading_done = true;
+                        // a broken direction takes the other one down
+                        if !matches!(read_result, Ok(Ok(()))) {
+                            writing_handle.abort();
+                            break read_result;
+                        }
+                    }
+                    write_result = &mut writing_handle, if !writing_done => {
+                        writing_done = true;
+                        if !matches!(write_result, Ok(Ok(()))) {
+                            reading_handle.abort();
+                            break write_result;
+                        }
+                    }
+                    _ = connection.cancelled() => {
+                        // dropping both halves closes the channel and the service connection
+                        writing_handle.abort();
+                        reading_handle.abort();
+                        break Ok(Ok(()));
                     }
-                    // dbg!(&n);
-                    if let Err(e) = tx.write_all(&buf[..n]).await {
-                        return Err(TunnelError::Io(e, "bad_read".to_string()));
+                    _ = idle(&activity, policy.idle.unwrap_or_default()), if policy.idle.is_some() => {
+                        writing_handle.abort();
+                        reading_handle.abort();
+                        break Ok(Err(TunnelError::IdleTimeout(policy.idle.unwrap_or_default())));
+                    }
+                    _ = &mut lifetime, if policy.lifetime.is_some() => {
+                        writing_handle.abort();
+                        reading_handle.abort();
+                        break Ok(Err(TunnelError::LifetimeExceeded(policy.lifetime.unwrap_or_default())));
                     }
                 }
-            }
-            Ok(())
+                // the connection is over only once both directions are
+                if reading_done && writing_done {
+                    break Ok(Ok(()));
+                }
+            };
+            drop(connection);
+            result
         });
-        let writing_handle: JoinHandle<Result<(), TunnelError>> = tokio::spawn(async move {
-            loop {
-                let mut buf = vec![0u8; 4096];
-                if let Ok(n) = rx.read(&mut buf).await {
-                    if n == 0 {
-                        break;
+        Ok(select_future)
+    }
+}
+
+impl TunnelRunner {
+    /// the tunneled service may be restarting, connecting is retried a few times. The
+    /// connect timeout bounds every attempt and the waits in between altogether, so
+    /// that the client isn't kept waiting for long
+    async fn connect_backend(&self) -> Result<TcpStream, TunnelError> {
+        let deadline = self
+            .policy
+            .backend_connect
+            .map(|budget| Instant::now() + budget);
+        let mut delay = BACKEND_RETRY_DELAY;
+        let mut retries = self.policy.backend_retries;
+        loop {
+            match self.try_connect_backend(deadline).await {
+                Ok(conn) => return Ok(conn),
+                Err(e)
+                    if retries > 0
+                        && deadline.is_none_or(|deadline| Instant::now() + delay < deadline) =>
+                {
+                    debug!(
+                        "cannot reach {}:{}: {}, retrying in {delay:?}",
+                        self.to_addr,
+                        self.to_port,
+                        e.to_string()
+                    );
+                    retries -= 1;
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    async fn try_connect_backend(
+        &self,
+        deadline: Option<Instant>,
+    ) -> Result<TcpStream, TunnelError> {
+        let target = (self.to_addr.to_string(), self.to_port);
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, TcpStream::connect(target))
+                .await
+                .map_err(|_| {
+                    TunnelError::BackendTimeout(format!("{}:{}", self.to_addr, self.to_port))
+                })?
+                .map_err(TunnelError::from),
+            None => Ok(TcpStream::connect(target).await?),
+        }
+    }
+}
+
+/// why a connection wasn't handed over to its tunnel
+#[derive(Debug, PartialEq)]
+pub(crate) enum Refused {
+    /// too many connections are waiting for the dispatcher already
+    QueueFull,
+    /// the tunnel stopped
+    TunnelGone,
+}
+
+/// hands a connection over to the dispatcher without waiting for room, it's refused
+/// when `tx` is full or closed
+pub(crate) async fn enqueue<C: ClientChannel>(
+    tx: &Sender<(TunnelRunner, C)>,
+    runner: TunnelRunner,
+    channel: C,
+    connections: &ConnectionRegistry,
+) -> Result<(), Refused> {
+    match tx.try_send((runner, channel)) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full((runner, channel))) => {
+            connections.rejected();
+            runner.refuse(channel).await;
+            Err(Refused::QueueFull)
+        }
+        Err(TrySendError::Closed((_, channel))) => {
+            channel.close_with(None).await;
+            Err(Refused::TunnelGone)
+        }
+    }
+}
+
+/// serves the connections received through `rx`, at most `max_connections` at once.
+/// Over the limit, the next connection waits up to `queue_timeout` for another one to
+/// end and is refused otherwise: the following ones wait in `rx` meanwhile, and are
+/// refused by `enqueue` once it's full
+pub(crate) async fn dispatch(
+    name: String,
+    mut rx: Receiver<(TunnelRunner, impl ClientChannel)>,
+    connections: ConnectionRegistry,
+    drain_tx: Sender<()>,
+    max_connections: usize,
+    queue_timeout: Duration,
+) {
+    let permits = Arc::new(Semaphore::new(max_connections));
+    while let Some((runner, chan)) = rx.recv().await {
+        let drain_guard = drain_tx.clone();
+        let Ok(Ok(permit)) =
+            tokio::time::timeout(queue_timeout, permits.clone().acquire_owned()).await
+        else {
+            connections.rejected();
+            warn!(
+                "tunnel `{name}` refusing a connection, {max_connections} connections are open already, {} refused so far",
+                connections.rejections()
+            );
+            // the next connections may find room meanwhile
+            tokio::spawn(async move {
+                runner.refuse(chan).await;
+                drop(drain_guard);
+            });
+            continue;
+        };
+        let name = name.to_owned();
+        let connections = connections.clone();
+        // connecting to the tunneled service may take a while, it doesn't hold the
+        // other connections back
+        tokio::spawn(async move {
+            let target = format!("{}:{}", runner.addr(), runner.port());
+            match runner.run(chan).await {
+                // a connection that can't be served has its channel closed by the
+                // runner, the tunnel keeps serving the others
+                Err(e) => error!(
+                    "tunnel `{name}` cannot reach {target}: {}, {} connections failed so far",
+                    e.to_string(),
+                    connections.failures()
+                ),
+                // idle and lifetime limits close connections with an error
+                Ok(connection) => {
+                    if let Ok(Ok(Err(e))) = connection.await {
+                        info!("tunnel `{name}` to {target}: {}", e.to_string());
                     }
-                    dbg!(&n);
-                    if let Err(e) = writer.write_all(&buf[..n]).await {
-                        return Err(TunnelError::Io(e, "bad_write".to_string()));
-                    };
                 }
             }
-            Ok(())
+            drop(permit);
+            drop(drain_guard);
         });
-        let select_future = tokio::spawn(async move {
-            tokio::select! {
-                write_result = writing_handle => {
-                    write_result
-                },
-                read_result = reading_handle => {
-                    read_result
-                }
+    }
+}
+
+fn io_error(e: std::io::Error, direction: &str, side: &str) -> TunnelError {
+    let message = format!("{direction}: {side}: {e}");
+    TunnelError::Io(e, message)
+}
+
+/// copies `reader` into `writer` until EOF, then shuts `writer` down so that the other
+/// side gets EOF too. `transferred` is told the size of every chunk, errors tell which
+/// side failed
+async fn pipe<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    transferred: impl Fn(usize),
+) -> Result<(), (std::io::Error, &'static str)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    loop {
+        let n = match reader.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err((e, "read failed")),
+        };
+        writer
+            .write_all(&buf[..n])
+            .await
+            .map_err(|e| (e, "write failed"))?;
+        transferred(n);
+    }
+    writer.shutdown().await.map_err(|e| (e, "shutdown failed"))
+}
+
+/// resolves once nothing has been transferred for `timeout`
+async fn idle(counters: &Counters, timeout: Duration) {
+    loop {
+        let idle_for = counters.idle_for();
+        if idle_for >= timeout {
+            return;
+        }
+        tokio::time::sleep(timeout - idle_for).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        sync::atomic::{AtomicUsize, Ordering},
+        task::{Context, Poll},
+        time::Instant,
+    };
+
+    use tokio::{
+        io::{DuplexStream, ReadBuf},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::tunneling::connections::{ConnectionRegistry, ConnectionState};
+
+    /// stands in for the ssh channel, the client is the other end of the duplex
+    impl ClientChannel for DuplexStream {
+        type Stream = DuplexStream;
+        async fn close_with(mut self, response: Option<&'static [u8]>) {
+            if let Some(response) = response {
+                let _ = self.write_all(response).await;
             }
+            let _ = self.shutdown().await;
+        }
+        fn into_stream(self) -> Self::Stream {
+            self
+        }
+    }
+
+    fn runner(registry: &ConnectionRegistry, port: u16, policy: ConnectionPolicy) -> TunnelRunner {
+        let connection = registry
+            .register(String::from("10.0.0.1:50000"), None)
+            .unwrap();
+        TunnelRunner::new("127.0.0.1", port, connection, policy).unwrap()
+    }
+
+    /// a local port nothing listens on, connecting to it is refused
+    async fn refused_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn unreachable_services_get_a_bad_gateway_after_retrying() {
+        let registry = ConnectionRegistry::default();
+        let policy = ConnectionPolicy {
+            backend_retries: 2,
+            bad_gateway: true,
+            ..Default::default()
+        };
+        let (channel, mut client) = tokio::io::duplex(1024);
+        let started = Instant::now();
+        let run = runner(&registry, refused_port().await, policy)
+            .run(channel)
+            .await;
+        // retried after 100ms, then after 200ms more: a third retry would wait 400ms
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(300) && elapsed < Duration::from_millis(700),
+            "{elapsed:?}"
+        );
+        assert!(matches!(run, Err(TunnelError::Io(..))));
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, BAD_GATEWAY);
+        assert_eq!(registry.failures(), 1);
+        assert!(registry.is_empty());
+
+        // the next connection is served as usual
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let serving = tokio::spawn(async move {
+            let (mut conn, _) = service.accept().await.unwrap();
+            conn.write_all(b"hello").await.unwrap();
         });
-        Ok(select_future)
+        let (channel, mut client) = tokio::io::duplex(1024);
+        let run = runner(&registry, port, policy).run(channel).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+        run.await.unwrap().unwrap().unwrap();
+        serving.await.unwrap();
+        assert_eq!(registry.failures(), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_stop_once_the_connect_timeout_is_spent() {
+        let registry = ConnectionRegistry::default();
+        let policy = ConnectionPolicy {
+            backend_connect: Some(Duration::from_millis(250)),
+            backend_retries: 10,
+            ..Default::default()
+        };
+        let (channel, _client) = tokio::io::duplex(1024);
+        let started = Instant::now();
+        let run = runner(&registry, refused_port().await, policy)
+            .run(channel)
+            .await;
+        // retried after 100ms, waiting 200ms more would outlast the timeout
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(100) && elapsed < Duration::from_millis(250),
+            "{elapsed:?}"
+        );
+        assert!(run.is_err());
+        assert_eq!(registry.failures(), 1);
+    }
+
+    #[tokio::test]
+    async fn unreachable_services_just_close_other_channels() {
+        let registry = ConnectionRegistry::default();
+        let (channel, mut client) = tokio::io::duplex(1024);
+        let run = runner(&registry, refused_port().await, ConnectionPolicy::default())
+            .run(channel)
+            .await;
+        assert!(run.is_err());
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+        assert_eq!(registry.failures(), 1);
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_limit_wait_their_turn_then_are_refused() {
+        // answers once the client is done sending
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = service.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = vec![];
+                    conn.read_to_end(&mut request).await.unwrap();
+                    conn.write_all(b"done").await.unwrap();
+                });
+            }
+        });
+        let registry = ConnectionRegistry::default();
+        let policy = ConnectionPolicy {
+            bad_gateway: true,
+            ..Default::default()
+        };
+        let (tx, rx) = tokio::sync::mpsc::channel(8);
+        let (drain_tx, mut drain_rx) = tokio::sync::mpsc::channel(1);
+        let dispatcher = tokio::spawn(dispatch(
+            String::from("web"),
+            rx,
+            registry.clone(),
+            drain_tx,
+            1,
+            Duration::from_millis(200),
+        ));
+
+        let (first, mut first_client) = tokio::io::duplex(1024);
+        tx.send((runner(&registry, port, policy), first))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(registry.list()[0].state, ConnectionState::Open);
+
+        // the first connection is still open when the queue timeout expires
+        let (second, mut second_client) = tokio::io::duplex(1024);
+        let started = Instant::now();
+        tx.send((runner(&registry, port, policy), second))
+            .await
+            .unwrap();
+        let mut response = vec![];
+        second_client.read_to_end(&mut response).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(response, SERVICE_UNAVAILABLE);
+        assert_eq!(registry.rejections(), 1);
+
+        // the first connection ends before the queue timeout expires
+        let (third, mut third_client) = tokio::io::duplex(1024);
+        tx.send((runner(&registry, port, policy), third))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        first_client.shutdown().await.unwrap();
+        let mut response = vec![];
+        first_client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"done");
+        third_client.shutdown().await.unwrap();
+        let mut response = vec![];
+        third_client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"done");
+        assert_eq!(registry.rejections(), 1);
+
+        drop(tx);
+        dispatcher.await.unwrap();
+        // every connection is over once nobody holds the drain channel
+        assert!(drain_rx.recv().await.is_none());
+        assert!(registry.is_empty());
+    }
+
+    #[tokio::test]
+    async fn connections_are_refused_once_the_queue_is_full() {
+        // keeps every connection open
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut open = vec![];
+            loop {
+                open.push(service.accept().await.unwrap());
+            }
+        });
+        let registry = ConnectionRegistry::default();
+        let policy = ConnectionPolicy {
+            bad_gateway: true,
+            ..Default::default()
+        };
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        let (drain_tx, _drain_rx) = tokio::sync::mpsc::channel(1);
+        tokio::spawn(dispatch(
+            String::from("web"),
+            rx,
+            registry.clone(),
+            drain_tx,
+            1,
+            Duration::from_secs(10),
+        ));
+
+        let mut clients = vec![];
+        // served, waiting for a permit, then waiting in the queue
+        for _ in 0..3 {
+            let (channel, client) = tokio::io::duplex(1024);
+            let queued = enqueue(&tx, runner(&registry, port, policy), channel, &registry).await;
+            assert_eq!(queued, Ok(()));
+            clients.push(client);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let (channel, mut client) = tokio::io::duplex(1024);
+        let queued = enqueue(&tx, runner(&registry, port, policy), channel, &registry).await;
+        assert_eq!(queued, Err(Refused::QueueFull));
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, SERVICE_UNAVAILABLE);
+        assert_eq!(registry.rejections(), 1);
+        assert_eq!(registry.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn connections_end_once_both_directions_are_done() {
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let (answer_tx, answer_rx) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(async move {
+            let (mut conn, _) = service.accept().await.unwrap();
+            let mut request = vec![];
+            conn.read_to_end(&mut request).await.unwrap();
+            // answers only once the client is done sending
+            answer_rx.await.unwrap();
+            conn.write_all(&request).await.unwrap();
+        });
+        let registry = ConnectionRegistry::default();
+        let (channel, mut client) = tokio::io::duplex(1024);
+        let run = runner(&registry, port, ConnectionPolicy::default())
+            .run(channel)
+            .await
+            .unwrap();
+
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // half-closed, the response can still come back
+        assert!(!run.is_finished());
+        assert_eq!(registry.list()[0].state, ConnectionState::Open);
+
+        answer_tx.send(()).unwrap();
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"request");
+        run.await.unwrap().unwrap().unwrap();
+        serving.await.unwrap();
+        assert!(registry.is_empty());
+        assert_eq!((registry.failures(), registry.rejections()), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn pipe_copies_until_eof_and_forwards_it() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let (mut client, mut near) = tokio::io::duplex(8192);
+        let (mut far, mut service) = tokio::io::duplex(8192);
+        let sent = data.clone();
+        let writing = tokio::spawn(async move {
+            client.write_all(&sent).await.unwrap();
+            // half-close: the client keeps reading
+            client.shutdown().await.unwrap();
+            client
+        });
+        let copied = AtomicUsize::new(0);
+        let reading = async {
+            let mut received = vec![];
+            service.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let (piped, received) = tokio::join!(
+            pipe(&mut near, &mut far, |n| {
+                copied.fetch_add(n, Ordering::Relaxed);
+            }),
+            reading
+        );
+        assert!(piped.is_ok());
+        assert_eq!(received, data);
+        assert_eq!(copied.load(Ordering::Relaxed), data.len());
+        writing.await.unwrap();
+    }
+
+    /// fails every read, a copy loop ignoring errors would spin forever on it
+    struct Broken;
+    impl AsyncRead for Broken {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn pipe_stops_on_read_errors() {
+        let (mut far, _service) = tokio::io::duplex(64);
+        let (e, side) = pipe(&mut Broken, &mut far, |_| {}).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset);
+        assert_eq!(side, "read failed");
     }
 }